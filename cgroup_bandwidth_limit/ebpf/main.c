@@ -1,37 +1,207 @@
 #include <linux/types.h>
 #include <linux/bpf.h>
+#include <linux/if_ether.h>
+#include <linux/in.h>
+#include <linux/ip.h>
+#include <linux/ipv6.h>
+#include <linux/pkt_cls.h>
 #include <bpf/bpf_helpers.h>
+#include <bpf/bpf_endian.h>
 
 #define IFINDEX_LO 1
+#define MAX_FLOWS 4096
 
+#define NSEC_PER_SEC 1000000000ULL
+// pending send time after which ECN capable packets are marked
+#define ECN_THRESHOLD_NS (10 * 1000000ULL)
+// pending send time after which packets are dropped when pacing / marking
+#define HORIZON_NS (2 * NSEC_PER_SEC)
+// attempts to advance the departure time shared by all CPUs
+#define DEPARTURE_RETRIES 4
+
+// cgroup_skb return codes, the second bit signals congestion to the local
+// TCP stack (egress only)
 enum {
 	DROP = 0,
 	ALLOW = 1,
+	DROP_CN = 2,
+	ALLOW_CN = 3,
+};
+
+// keep in sync with `Enforce` in src/limiter.rs
+enum {
+	ENFORCE_DROP = 0,
+	ENFORCE_PACE = 1,
+	ENFORCE_ECN = 2,
 };
 
 // section starts with ".maps" ==> BTF style map definition
 struct {
 	__uint(type, BPF_MAP_TYPE_ARRAY);
-	__uint(max_entries, 2);
+	__uint(max_entries, 8);
 	__type(key, int);
 	__type(value, __u64);
 	__uint(map_flags, BPF_F_MMAPABLE);
 } globals SEC(".maps");
 
+// keep in sync with `FlowKey` in src/flows.rs
+struct flow_key {
+	// IPv4 addresses only use the first 4 bytes
+	__u8 remote_addr[16];
+	// hosts with several addresses may talk to the same remote from each
+	__u8 local_addr[16];
+	__u16 remote_port;
+	__u16 local_port;
+	__u8 family;
+	__u8 protocol;
+	__u8 _pad[2];
+};
+
+// keep in sync with `FlowValue` in src/flows.rs
+struct flow_value {
+	__u64 bytes;
+	__u64 packets;
+	__u64 drops;
+};
+
+struct {
+	__uint(type, BPF_MAP_TYPE_LRU_HASH);
+	__uint(max_entries, MAX_FLOWS);
+	__type(key, struct flow_key);
+	__type(value, struct flow_value);
+} flows SEC(".maps");
+
 #ifndef KIND
 #define KIND "cgroup_skb/ingress"
 #endif
 
+// for cgroup_skb programs the packet data starts at the network header
+#ifdef TC
+#define L3_OFFSET ETH_HLEN
+#else
+#define L3_OFFSET 0
+#endif
+
 const int BYTE_COUNT = 0;
 const int HARD_QUOTA = 1;
+const int RATE = 2;
+const int NEXT_DEPARTURE = 3;
+// may be changed at runtime, e.g. by a schedule
+const int ENFORCE = 4;
+// CREDIT = 5 and DEBT = 6 are only used by the userspace helper
+const int BORROW = 7;
 
-SEC(KIND)
-int bandwidth_limit(struct __sk_buff *skb) {
-	// ignore loopback traffic
-	if (skb->ifindex == IFINDEX_LO) {
-		return ALLOW;
+// set by the userspace helper before loading
+volatile const __u8 track_flows = 0;
+// tc programs see all traffic of the interface, only handle the target cgroup
+volatile const __u64 cgroup_id = 0;
+volatile const __s32 cgroup_level = 0;
+
+static __always_inline int parse_flow(struct __sk_buff *skb, struct flow_key *key) {
+	__u32 l4_offset;
+	__u16 ports[2];
+
+	if (skb->protocol == bpf_htons(ETH_P_IP)) {
+		struct iphdr ip;
+		if (bpf_skb_load_bytes(skb, L3_OFFSET, &ip, sizeof(ip)) < 0) {
+			return -1;
+		}
+		key->family = 4;
+		key->protocol = ip.protocol;
+#ifdef EGRESS
+		__builtin_memcpy(key->remote_addr, &ip.daddr, sizeof(ip.daddr));
+		__builtin_memcpy(key->local_addr, &ip.saddr, sizeof(ip.saddr));
+#else
+		__builtin_memcpy(key->remote_addr, &ip.saddr, sizeof(ip.saddr));
+		__builtin_memcpy(key->local_addr, &ip.daddr, sizeof(ip.daddr));
+#endif
+		l4_offset = L3_OFFSET + ip.ihl * 4;
+	} else if (skb->protocol == bpf_htons(ETH_P_IPV6)) {
+		struct ipv6hdr ip6;
+		if (bpf_skb_load_bytes(skb, L3_OFFSET, &ip6, sizeof(ip6)) < 0) {
+			return -1;
+		}
+		key->family = 6;
+		// extension headers are not followed
+		key->protocol = ip6.nexthdr;
+#ifdef EGRESS
+		__builtin_memcpy(key->remote_addr, &ip6.daddr, sizeof(ip6.daddr));
+		__builtin_memcpy(key->local_addr, &ip6.saddr, sizeof(ip6.saddr));
+#else
+		__builtin_memcpy(key->remote_addr, &ip6.saddr, sizeof(ip6.saddr));
+		__builtin_memcpy(key->local_addr, &ip6.daddr, sizeof(ip6.daddr));
+#endif
+		l4_offset = L3_OFFSET + sizeof(ip6);
+	} else {
+		return -1;
+	}
+
+	if (key->protocol != IPPROTO_TCP && key->protocol != IPPROTO_UDP) {
+		return 0;
+	}
+	// source and destination port are the first 4 bytes of tcp and udp headers
+	if (bpf_skb_load_bytes(skb, l4_offset, ports, sizeof(ports)) < 0) {
+		return 0;
+	}
+#ifdef EGRESS
+	key->remote_port = bpf_ntohs(ports[1]);
+	key->local_port = bpf_ntohs(ports[0]);
+#else
+	key->remote_port = bpf_ntohs(ports[0]);
+	key->local_port = bpf_ntohs(ports[1]);
+#endif
+	return 0;
+}
+
+static __always_inline void account_flow(struct __sk_buff *skb, int verdict) {
+	struct flow_key key = {};
+	if (parse_flow(skb, &key) < 0) {
+		return;
 	}
 
+	struct flow_value *value = bpf_map_lookup_elem(&flows, &key);
+	if (value == NULL) {
+		struct flow_value zero = {};
+		bpf_map_update_elem(&flows, &key, &zero, BPF_NOEXIST);
+		value = bpf_map_lookup_elem(&flows, &key);
+		if (value == NULL) {
+			return;
+		}
+	}
+
+	if (verdict & ALLOW) {
+		__sync_fetch_and_add(&value->bytes, (__u64)skb->len);
+		__sync_fetch_and_add(&value->packets, 1);
+	} else {
+		__sync_fetch_and_add(&value->drops, 1);
+	}
+}
+
+// Earliest departure time of the packet if the cgroup sent at exactly `rate`
+// bytes per second. Advances the virtual clock `next` which is shared by all
+// CPUs: the update is a compare and swap, retried a few times when another CPU
+// got in between. If all attempts fail the delay of the packet is added
+// atomically instead, this never loses bytes but does not move an idle clock
+// up to `now` first, so the rate may be exceeded briefly under contention.
+static __always_inline __u64 departure(__u64 *next, __u64 rate, __u32 len, __u64 now) {
+	__u64 delay = (__u64)len * NSEC_PER_SEC / rate;
+
+	for (int i = 0; i < DEPARTURE_RETRIES; i++) {
+		__u64 old = *(volatile __u64 *)next;
+		__u64 t = old > now ? old : now;
+		if (t - now > HORIZON_NS) {
+			return t;
+		}
+		if (__sync_val_compare_and_swap(next, old, t + delay) == old) {
+			return t;
+		}
+	}
+
+	__u64 old = __sync_fetch_and_add(next, delay);
+	return old > now ? old : now;
+}
+
+static __always_inline int limit(struct __sk_buff *skb) {
 	__u64* byte_count = bpf_map_lookup_elem(&globals, &BYTE_COUNT);
 	if (byte_count == NULL) {
 		return ALLOW;
@@ -39,13 +209,73 @@ int bandwidth_limit(struct __sk_buff *skb) {
 
 	__u64* el = bpf_map_lookup_elem(&globals, &HARD_QUOTA);
 	__u64 hard_quota = el == NULL ? 0 : *el;
+	// bytes which may be used on top of the quota, deducted from the next period
+	el = bpf_map_lookup_elem(&globals, &BORROW);
+	__u64 borrow = el == NULL ? 0 : *el;
 
-	if (hard_quota > 0 && *byte_count >= hard_quota) {
+	if (hard_quota > 0 && *byte_count >= hard_quota + borrow) {
 		return DROP;
 	}
 
+	int verdict = ALLOW;
+	__u64* rate = bpf_map_lookup_elem(&globals, &RATE);
+	__u64* next = bpf_map_lookup_elem(&globals, &NEXT_DEPARTURE);
+	el = bpf_map_lookup_elem(&globals, &ENFORCE);
+	__u64 enforce = el == NULL ? ENFORCE_DROP : *el;
+	if (enforce != ENFORCE_DROP && rate != NULL && next != NULL && *rate > 0) {
+		__u64 now = bpf_ktime_get_ns();
+		__u64 t = departure(next, *rate, skb->len, now);
+		if (t - now > HORIZON_NS) {
+			return DROP_CN;
+		}
+#ifdef TC
+		if (enforce == ENFORCE_PACE) {
+			// fq holds the packet back until its departure time
+			skb->tstamp = t;
+		}
+#else
+		if (enforce == ENFORCE_ECN && t - now > ECN_THRESHOLD_NS) {
+			// no-op unless ECN was negotiated, the CN bit still slows
+			// down local senders
+			bpf_skb_ecn_set_ce(skb);
+			verdict = ALLOW_CN;
+		}
+#endif
+	}
+
 	__sync_fetch_and_add(byte_count, (__u64)skb->len);
-	return ALLOW;
+	return verdict;
+}
+
+SEC(KIND)
+int bandwidth_limit(struct __sk_buff *skb) {
+	int verdict = ALLOW;
+
+	// ignore loopback traffic
+	if (skb->ifindex == IFINDEX_LO) {
+		goto out;
+	}
+
+#ifdef TC
+	if (bpf_skb_ancestor_cgroup_id(skb, cgroup_level) != cgroup_id) {
+		goto out;
+	}
+#endif
+
+	verdict = limit(skb);
+	if (track_flows) {
+		account_flow(skb, verdict);
+	}
+
+out:
+#ifdef TC
+	return verdict & ALLOW ? TC_ACT_OK : TC_ACT_SHOT;
+#elif defined(EGRESS)
+	return verdict;
+#else
+	// congestion notification is only supported on egress
+	return verdict & ALLOW;
+#endif
 }
 
 char _license[] SEC("license") = "MIT";
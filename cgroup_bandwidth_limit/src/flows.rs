@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use aya::maps::{Map, MapData};
+use serde::Serialize;
+
+/// Key of the `flows` map, see `struct flow_key` in ebpf/main.c
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    remote_addr: [u8; 16],
+    local_addr: [u8; 16],
+    remote_port: u16,
+    local_port: u16,
+    family: u8,
+    protocol: u8,
+    _pad: [u8; 2],
+}
+
+/// Value of the `flows` map, see `struct flow_value` in ebpf/main.c
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FlowValue {
+    bytes: u64,
+    packets: u64,
+    drops: u64,
+}
+
+unsafe impl aya::Pod for FlowKey {}
+unsafe impl aya::Pod for FlowValue {}
+
+pub type FlowMap = aya::maps::HashMap<MapData, FlowKey, FlowValue>;
+
+impl FlowKey {
+    fn addr(&self, addr: [u8; 16]) -> IpAddr {
+        if self.family == 4 {
+            let [a, b, c, d, ..] = addr;
+            IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+        } else {
+            IpAddr::V6(Ipv6Addr::from(addr))
+        }
+    }
+
+    fn protocol(&self) -> String {
+        match self.protocol as i32 {
+            libc::IPPROTO_TCP => "tcp".to_string(),
+            libc::IPPROTO_UDP => "udp".to_string(),
+            libc::IPPROTO_ICMP => "icmp".to_string(),
+            libc::IPPROTO_ICMPV6 => "icmpv6".to_string(),
+            p => p.to_string(),
+        }
+    }
+}
+
+/// Remove all flows, called at the start of every quota period.
+pub fn clear(flows: &mut FlowMap) {
+    let keys: Vec<_> = flows.keys().filter_map(Result::ok).collect();
+    for key in keys {
+        // the entry might have been evicted in the meantime
+        let _ = flows.remove(&key);
+    }
+}
+
+pub fn open_pinned(path: &std::path::Path) -> Result<FlowMap, anyhow::Error> {
+    let data = MapData::from_pin(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    Ok(FlowMap::try_from(Map::LruHashMap(data))?)
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DirectionStats {
+    pub bytes: u64,
+    pub packets: u64,
+    pub drops: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Flow {
+    pub remote_addr: IpAddr,
+    pub remote_port: u16,
+    pub local_addr: IpAddr,
+    pub local_port: u16,
+    pub protocol: String,
+    pub ingress: DirectionStats,
+    pub egress: DirectionStats,
+}
+
+impl Flow {
+    fn total_bytes(&self) -> u64 {
+        self.ingress.bytes + self.egress.bytes
+    }
+}
+
+/// Merge the flows of both directions and return the `top` flows by bytes.
+pub fn top_flows(ingress: &FlowMap, egress: &FlowMap, top: usize) -> Vec<Flow> {
+    let mut merged: HashMap<FlowKey, Flow> = HashMap::new();
+
+    for (map, is_ingress) in [(ingress, true), (egress, false)] {
+        for (key, value) in map.iter().filter_map(Result::ok) {
+            let flow = merged.entry(key).or_insert_with(|| Flow {
+                remote_addr: key.addr(key.remote_addr),
+                remote_port: key.remote_port,
+                local_addr: key.addr(key.local_addr),
+                local_port: key.local_port,
+                protocol: key.protocol(),
+                ingress: DirectionStats::default(),
+                egress: DirectionStats::default(),
+            });
+            let stats = if is_ingress {
+                &mut flow.ingress
+            } else {
+                &mut flow.egress
+            };
+            stats.bytes += value.bytes;
+            stats.packets += value.packets;
+            stats.drops += value.drops;
+        }
+    }
+
+    let mut flows: Vec<_> = merged.into_values().collect();
+    flows.sort_by_key(|f| std::cmp::Reverse(f.total_bytes()));
+    flows.truncate(top);
+    flows
+}
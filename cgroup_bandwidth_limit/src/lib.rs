@@ -0,0 +1,22 @@
+//! Cgroup bandwidth limiting using eBPF.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use bandwidth_limit::{Limiter, Policy};
+//!
+//! let policy = Policy::new(10 * 1024 * 1024, Duration::from_secs(10));
+//! let mut limiter = Limiter::attach("/sys/fs/cgroup/foo", policy)?;
+//! println!("{:?}", limiter.usage());
+//! limiter.set_quota(0);
+//! limiter.detach();
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+pub mod flows;
+mod limiter;
+pub mod mmap;
+pub mod schedule;
+
+pub use limiter::{
+    pinned_counters, pinned_map, Counters, Direction, Enforce, Limiter, Policy, Usage,
+};
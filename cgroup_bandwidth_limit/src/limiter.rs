@@ -0,0 +1,606 @@
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use aya::maps::{Array, IterableMap, MapData};
+use aya::programs::{tc, CgroupSkb, CgroupSkbAttachType, SchedClassifier, TcAttachType};
+use aya::{include_bytes_aligned, Bpf, BpfLoader};
+use log::info;
+use serde::Serialize;
+
+use crate::flows::{self, Flow, FlowMap};
+use crate::mmap::{MmapArray, ReadOnly};
+
+// indices into the `globals` array, see ebpf/main.c
+const BYTE_COUNT: u32 = 0;
+const HARD_QUOTA: u32 = 1;
+const RATE: u32 = 2;
+// NEXT_DEPARTURE = 3 is only used by the BPF programs
+const ENFORCE: u32 = 4;
+const CREDIT: u32 = 5;
+const DEBT: u32 = 6;
+const BORROW: u32 = 7;
+const GLOBALS_LEN: u32 = 8;
+
+/// Keep in sync with the `ENFORCE_*` constants in ebpf/main.c
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Enforce {
+    /// Drop packets once the quota is exceeded
+    Drop = 0,
+    /// Delay egress packets using earliest departure time (requires the fq qdisc)
+    Pace = 1,
+    /// Mark packets with ECN CE and signal congestion to local senders
+    Ecn = 2,
+}
+
+impl Enforce {
+    pub const ALL: [Enforce; 3] = [Enforce::Drop, Enforce::Pace, Enforce::Ecn];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Enforce::Drop => "drop",
+            Enforce::Pace => "pace",
+            Enforce::Ecn => "ecn",
+        }
+    }
+
+    /// Whether a limiter attached with `self` can switch to `other` without reattaching:
+    /// pacing attaches the tc egress program, which cannot mark ECN, and the cgroup egress
+    /// program cannot pace.
+    pub fn can_switch_to(self, other: Enforce) -> bool {
+        !matches!(
+            (self, other),
+            (Enforce::Pace, Enforce::Ecn) | (Enforce::Drop | Enforce::Ecn, Enforce::Pace)
+        )
+    }
+}
+
+impl FromStr for Enforce {
+    type Err = String;
+
+    /// `drop`, `pace` or `ecn`, ignoring case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Enforce::ALL
+            .into_iter()
+            .find(|e| s.eq_ignore_ascii_case(e.as_str()))
+            .ok_or_else(|| format!("invalid enforcement '{}', expected drop, pace or ecn", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Ingress,
+    Egress,
+}
+
+impl Direction {
+    pub const ALL: [Direction; 2] = [Direction::Ingress, Direction::Egress];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Ingress => "ingress",
+            Direction::Egress => "egress",
+        }
+    }
+
+    fn attach_type(&self) -> CgroupSkbAttachType {
+        match self {
+            Direction::Ingress => CgroupSkbAttachType::Ingress,
+            Direction::Egress => CgroupSkbAttachType::Egress,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// Allowed bytes for ingress / egress each per quota period, 0 means unlimited
+    pub quota: u64,
+    /// Used to derive the rate for `Enforce::Pace` and `Enforce::Ecn`
+    pub quota_period: Duration,
+    /// Rate in bytes per second for `Enforce::Pace` and `Enforce::Ecn`, defaults to
+    /// quota / quota period
+    pub rate: Option<u64>,
+    pub enforce: Enforce,
+    /// Interface to attach the egress pacing program to, required for `Enforce::Pace`
+    pub interface: Option<String>,
+    /// Maintain per-flow counters, see `Limiter::top_flows`
+    pub track_flows: bool,
+    /// Maximum number of unused bytes carried over into the next period, 0 disables rollover
+    pub rollover_cap: u64,
+    /// Percentage of the quota which may be used on top of it, deducted from the next period.
+    /// 0 disables borrowing, at most 100
+    pub borrow_percent: u64,
+}
+
+impl Policy {
+    pub fn new(quota: u64, quota_period: Duration) -> Self {
+        Self {
+            quota,
+            quota_period,
+            rate: None,
+            enforce: Enforce::Drop,
+            interface: None,
+            track_flows: false,
+            rollover_cap: 0,
+            borrow_percent: 0,
+        }
+    }
+
+    fn rate(&self) -> u64 {
+        self.rate
+            .unwrap_or(self.quota / self.quota_period.as_secs().max(1))
+    }
+
+    /// Bytes which may be borrowed per period.
+    fn borrow(&self) -> u64 {
+        // at most the quota as borrow_percent <= 100
+        (self.quota as u128 * self.borrow_percent.min(100) as u128 / 100) as u64
+    }
+
+    fn check(&self) -> Result<(), anyhow::Error> {
+        if self.borrow_percent > 100 {
+            anyhow::bail!(
+                "cannot borrow {}% of the quota, at most 100% are allowed",
+                self.borrow_percent
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Credit and debt for the next period after `used` bytes were counted in a period which
+/// started with `credit` and `debt`. Both are measured against the base quota: the bytes are
+/// taken from the credit first (unused credit expires), the debt is repaid from the base quota.
+fn carry_over(policy: &Policy, used: u64, credit: u64, debt: u64) -> (u64, u64) {
+    if policy.quota == 0 {
+        return (0, 0);
+    }
+    let used = used.saturating_sub(credit).saturating_add(debt);
+    if used <= policy.quota {
+        ((policy.quota - used).min(policy.rollover_cap), 0)
+    } else {
+        // with borrowing disabled only the overshoot of the last packet (or all of the
+        // excess with pacing / ECN) exceeds the quota, it is not carried over
+        (0, (used - policy.quota).min(policy.borrow()))
+    }
+}
+
+/// State of one direction in the current quota period.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Counters {
+    pub bytes: u64,
+    /// Quota of this period including credit and debt, 0 means unlimited
+    pub quota: u64,
+    /// Unused bytes rolled over from previous periods
+    pub credit: u64,
+    /// Bytes borrowed in the previous period
+    pub debt: u64,
+    /// Bytes which may be used on top of `quota`
+    pub borrow: u64,
+}
+
+impl Counters {
+    fn read<A>(globals: &MmapArray<u64, A>) -> Self {
+        // the mapping was checked to have GLOBALS_LEN entries
+        let get = |index| globals.get(index).expect("index below GLOBALS_LEN");
+        Self {
+            bytes: get(BYTE_COUNT),
+            quota: get(HARD_QUOTA),
+            credit: get(CREDIT),
+            debt: get(DEBT),
+            borrow: get(BORROW),
+        }
+    }
+}
+
+/// Bytes counted in the current quota period.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Usage {
+    pub ingress: u64,
+    pub egress: u64,
+}
+
+impl Usage {
+    pub fn get(&self, dir: Direction) -> u64 {
+        match dir {
+            Direction::Ingress => self.ingress,
+            Direction::Egress => self.egress,
+        }
+    }
+}
+
+/// Programs and maps of one direction.
+struct Hook {
+    dir: Direction,
+    // egress is handled by a tc program instead of the cgroup hook
+    tc: bool,
+    globals: MmapArray<u64>,
+    globals_map: Array<MapData, u64>,
+    flows: FlowMap,
+    // dropping the programs detaches them
+    _bpf: Bpf,
+}
+
+/// Bandwidth limit attached to a cgroup. Dropping it detaches the limit.
+pub struct Limiter {
+    cgroup: PathBuf,
+    policy: Policy,
+    ingress: Hook,
+    egress: Hook,
+}
+
+fn is_cgroup2(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut buf: libc::statfs = unsafe { core::mem::zeroed() };
+    let ret = unsafe { libc::statfs(path.as_ptr(), &mut buf) };
+    ret == 0 && buf.f_type == libc::CGROUP2_SUPER_MAGIC
+}
+
+/// Returns the id (inode number) and the level in the hierarchy of the cgroup.
+fn cgroup_id(cgroup: &Path) -> Result<(u64, i32), anyhow::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let id = std::fs::metadata(cgroup)?.ino();
+    let mut level = 0;
+    let mut path = cgroup;
+    while let Some(parent) = path.parent() {
+        if !is_cgroup2(parent) {
+            break;
+        }
+        level += 1;
+        path = parent;
+    }
+
+    Ok((id, level))
+}
+
+fn load_program(cgroup: &Path, policy: &Policy, dir: Direction) -> Result<Bpf, anyhow::Error> {
+    let track_flows = policy.track_flows as u8;
+
+    // received packets cannot be delayed
+    if policy.enforce == Enforce::Pace && dir == Direction::Egress {
+        let iface = policy
+            .interface
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("pacing requires an interface"))?;
+        let (id, level) = cgroup_id(cgroup)?;
+        let mut bpf = BpfLoader::new()
+            .set_global("track_flows", &track_flows, true)
+            .set_global("cgroup_id", &id, true)
+            .set_global("cgroup_level", &level, true)
+            .load(include_bytes_aligned!("../ebpf/ebpf_tc.o"))?;
+
+        // fails if the interface already has a clsact qdisc which is fine
+        let _ = tc::qdisc_add_clsact(iface);
+        let program: &mut SchedClassifier =
+            bpf.program_mut("bandwidth_limit").unwrap().try_into()?;
+        program.load()?;
+        program.attach(iface, TcAttachType::Egress)?;
+
+        info!("{} loaded on {}", dir.as_str(), iface);
+        info!(
+            "pacing needs the fq qdisc: tc qdisc replace dev {} root fq",
+            iface
+        );
+        return Ok(bpf);
+    }
+
+    let raw = match dir {
+        Direction::Ingress => include_bytes_aligned!("../ebpf/ebpf_ingress.o"),
+        Direction::Egress => include_bytes_aligned!("../ebpf/ebpf_egress.o"),
+    };
+    let mut bpf = BpfLoader::new()
+        .set_global("track_flows", &track_flows, true)
+        .load(raw)?;
+
+    let program: &mut CgroupSkb = bpf.program_mut("bandwidth_limit").unwrap().try_into()?;
+    let cgroup = std::fs::File::open(cgroup)?;
+    program.load()?;
+    program.attach(cgroup, dir.attach_type())?;
+
+    info!("{} loaded", dir.as_str());
+    Ok(bpf)
+}
+
+impl Hook {
+    /// The global at `index`, the mapping was checked to have `GLOBALS_LEN` entries.
+    fn global(&self, index: u32) -> &AtomicU64 {
+        self.globals.atomic(index).expect("index below GLOBALS_LEN")
+    }
+
+    fn byte_count(&self) -> u64 {
+        self.global(BYTE_COUNT).load(Ordering::Relaxed)
+    }
+
+    /// Start a new quota period, unused bytes become credit and borrowed bytes debt.
+    fn reset(&mut self, policy: &Policy) {
+        let used = self.global(BYTE_COUNT).swap(0, Ordering::Relaxed);
+        let (credit, debt) = carry_over(
+            policy,
+            used,
+            self.global(CREDIT).load(Ordering::Relaxed),
+            self.global(DEBT).load(Ordering::Relaxed),
+        );
+        self.global(CREDIT).store(credit, Ordering::Relaxed);
+        self.global(DEBT).store(debt, Ordering::Relaxed);
+        // the enforcement did not change, this cannot fail
+        let _ = self.apply(policy);
+
+        if policy.track_flows {
+            flows::clear(&mut self.flows);
+        }
+    }
+
+    /// Map the requested enforcement to the one supported by the attached program.
+    fn enforce(&self, enforce: Enforce) -> Result<Enforce, anyhow::Error> {
+        match (self.dir, self.tc, enforce) {
+            // received packets cannot be delayed
+            (Direction::Ingress, _, Enforce::Pace) => Ok(Enforce::Drop),
+            (_, true, Enforce::Ecn) | (Direction::Egress, false, Enforce::Pace) => Err(
+                anyhow::anyhow!("cannot switch to {:?} without reattaching", enforce),
+            ),
+            (_, _, enforce) => Ok(enforce),
+        }
+    }
+
+    fn apply(&self, policy: &Policy) -> Result<(), anyhow::Error> {
+        let enforce = self.enforce(policy.enforce)?;
+
+        let (quota, borrow) = if policy.quota == 0 {
+            (0, 0)
+        } else {
+            let credit = self.global(CREDIT).load(Ordering::Relaxed);
+            let debt = self.global(DEBT).load(Ordering::Relaxed);
+            // 0 would mean unlimited
+            let quota = policy
+                .quota
+                .saturating_add(credit)
+                .saturating_sub(debt)
+                .max(1);
+            (quota, policy.borrow())
+        };
+        self.global(HARD_QUOTA).store(quota, Ordering::Relaxed);
+        self.global(BORROW).store(borrow, Ordering::Relaxed);
+        self.global(RATE).store(policy.rate(), Ordering::Relaxed);
+        self.global(ENFORCE)
+            .store(enforce as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn attach(cgroup: &Path, policy: &Policy, dir: Direction) -> Result<Self, anyhow::Error> {
+        let mut bpf = load_program(cgroup, policy, dir)?;
+
+        let globals_map = Array::<_, u64>::try_from(bpf.take_map("globals").unwrap())?;
+        let flows = FlowMap::try_from(bpf.take_map("flows").unwrap())?;
+
+        let globals = unsafe { MmapArray::<u64>::new(globals_map.map().fd().as_fd(), GLOBALS_LEN) }
+            .map_err(|e| anyhow::anyhow!("failed to map globals: {}", e))?;
+        let hook = Self {
+            dir,
+            tc: policy.enforce == Enforce::Pace && dir == Direction::Egress,
+            globals,
+            globals_map,
+            flows,
+            _bpf: bpf,
+        };
+        hook.apply(policy)?;
+
+        Ok(hook)
+    }
+}
+
+impl Limiter {
+    /// Load and attach the limiter programs for both directions.
+    pub fn attach(cgroup: impl AsRef<Path>, policy: Policy) -> Result<Self, anyhow::Error> {
+        policy.check()?;
+        let cgroup = cgroup.as_ref().to_path_buf();
+        let ingress = Hook::attach(&cgroup, &policy, Direction::Ingress)?;
+        let egress = Hook::attach(&cgroup, &policy, Direction::Egress)?;
+
+        Ok(Self {
+            cgroup,
+            policy,
+            ingress,
+            egress,
+        })
+    }
+
+    fn hooks(&self) -> [&Hook; 2] {
+        [&self.ingress, &self.egress]
+    }
+
+    fn hooks_mut(&mut self) -> [&mut Hook; 2] {
+        [&mut self.ingress, &mut self.egress]
+    }
+
+    pub fn cgroup(&self) -> &Path {
+        &self.cgroup
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    pub fn usage(&self) -> Usage {
+        Usage {
+            ingress: self.ingress.byte_count(),
+            egress: self.egress.byte_count(),
+        }
+    }
+
+    /// Change the quota (and the rate derived from it) with immediate effect.
+    pub fn set_quota(&mut self, quota: u64) {
+        self.policy.quota = quota;
+        for hook in self.hooks() {
+            // the enforcement did not change, this cannot fail
+            let _ = hook.apply(&self.policy);
+        }
+    }
+
+    /// Switch to another policy with immediate effect.
+    ///
+    /// Quota, quota period, rate and enforcement can be changed, as long as the enforcement
+    /// does not require other programs (`Enforce::Pace` vs `Enforce::Ecn` on egress).
+    pub fn update(&mut self, policy: Policy) -> Result<(), anyhow::Error> {
+        if policy.interface != self.policy.interface
+            || policy.track_flows != self.policy.track_flows
+        {
+            anyhow::bail!("interface and flow tracking cannot be changed without reattaching");
+        }
+        policy.check()?;
+        for hook in self.hooks() {
+            hook.enforce(policy.enforce)?;
+        }
+        for hook in self.hooks() {
+            hook.apply(&policy)?;
+        }
+        self.policy = policy;
+        Ok(())
+    }
+
+    pub fn counters(&self, dir: Direction) -> Counters {
+        match dir {
+            Direction::Ingress => Counters::read(&self.ingress.globals),
+            Direction::Egress => Counters::read(&self.egress.globals),
+        }
+    }
+
+    /// Start a new quota period: reset the byte counters and the flow table and update
+    /// credit and debt.
+    pub fn reset(&mut self) {
+        let policy = self.policy.clone();
+        for hook in self.hooks_mut() {
+            hook.reset(&policy);
+        }
+    }
+
+    /// Pin the maps into `pin_path` (on a bpffs mount) so that they can be read by other
+    /// processes, see `pinned_counters` and `flows::open_pinned`.
+    ///
+    /// Credit and debt of a previous run pinned to the same path are carried over.
+    pub fn pin(&self, pin_path: &Path) -> Result<(), anyhow::Error> {
+        for hook in self.hooks() {
+            if let Ok(previous) = pinned_counters(pin_path, hook.dir) {
+                hook.global(CREDIT)
+                    .store(previous.credit, Ordering::Relaxed);
+                hook.global(DEBT).store(previous.debt, Ordering::Relaxed);
+                hook.apply(&self.policy)?;
+            }
+            pin(hook.globals_map.map(), pin_path, hook.dir, "globals")?;
+            if self.policy.track_flows {
+                pin(hook.flows.map(), pin_path, hook.dir, "flows")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The `top` flows by bytes of the current quota period.
+    pub fn top_flows(&self, top: usize) -> Vec<Flow> {
+        flows::top_flows(&self.ingress.flows, &self.egress.flows, top)
+    }
+
+    /// Detach the programs and release the maps.
+    pub fn detach(self) {
+        info!("detaching from {}", self.cgroup.display());
+    }
+}
+
+pub fn pinned_map(pin_path: &Path, dir: Direction, name: &str) -> PathBuf {
+    pin_path.join(format!("{}_{}", dir.as_str(), name))
+}
+
+fn pin(map: &MapData, pin_path: &Path, dir: Direction, name: &str) -> Result<(), anyhow::Error> {
+    let path = pinned_map(pin_path, dir, name);
+    // a stale pin of a previous run would make pinning fail
+    let _ = std::fs::remove_file(&path);
+    map.pin(&path)?;
+    info!("{} {} pinned to {}", dir.as_str(), name, path.display());
+    Ok(())
+}
+
+/// Read the counters of one direction from maps pinned with `Limiter::pin`.
+pub fn pinned_counters(pin_path: &Path, dir: Direction) -> Result<Counters, anyhow::Error> {
+    let path = pinned_map(pin_path, dir, "globals");
+    let data =
+        MapData::from_pin(&path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    let g = unsafe { MmapArray::<u64, ReadOnly>::read_only(data.fd().as_fd(), GLOBALS_LEN) }
+        .map_err(|e| anyhow::anyhow!("failed to map {}: {}", path.display(), e))?;
+
+    Ok(Counters::read(&g))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(rollover_cap: u64, borrow_percent: u64) -> Policy {
+        Policy {
+            rollover_cap,
+            borrow_percent,
+            ..Policy::new(1000, Duration::from_secs(10))
+        }
+    }
+
+    #[test]
+    fn carry_over_neither() {
+        let p = policy(0, 0);
+        assert_eq!(carry_over(&p, 400, 0, 0), (0, 0));
+        // the overshoot of the last packet (or pacing) does not become debt
+        assert_eq!(carry_over(&p, 1500, 0, 0), (0, 0));
+    }
+
+    #[test]
+    fn carry_over_rollover() {
+        let p = policy(300, 0);
+        assert_eq!(carry_over(&p, 900, 0, 0), (100, 0));
+        assert_eq!(carry_over(&p, 400, 0, 0), (300, 0));
+        // the credit is used first and does not compound
+        assert_eq!(carry_over(&p, 0, 300, 0), (300, 0));
+        assert_eq!(carry_over(&p, 1100, 300, 0), (200, 0));
+        assert_eq!(carry_over(&p, 1400, 300, 0), (0, 0));
+    }
+
+    #[test]
+    fn carry_over_borrow() {
+        let p = policy(0, 20);
+        assert_eq!(carry_over(&p, 1100, 0, 0), (0, 100));
+        // capped at the borrow limit, e.g. with pacing
+        assert_eq!(carry_over(&p, 5000, 0, 0), (0, 200));
+        // repaying the debt does not create new debt
+        assert_eq!(carry_over(&p, 800, 0, 200), (0, 0));
+        assert_eq!(carry_over(&p, 1000, 0, 200), (0, 200));
+        assert_eq!(carry_over(&p, 500, 0, 200), (0, 0));
+    }
+
+    #[test]
+    fn carry_over_both() {
+        let p = policy(300, 20);
+        assert_eq!(carry_over(&p, 1100, 0, 0), (0, 100));
+        // the repaid debt is not refunded as credit
+        assert_eq!(carry_over(&p, 900, 0, 100), (0, 0));
+        assert_eq!(carry_over(&p, 700, 0, 100), (200, 0));
+        assert_eq!(carry_over(&p, 1500, 300, 0), (0, 200));
+        // unlimited
+        assert_eq!(carry_over(&Policy { quota: 0, ..p }, 5000, 0, 0), (0, 0));
+    }
+
+    #[test]
+    fn borrow_limit() {
+        assert_eq!(policy(0, 20).borrow(), 200);
+        let p = Policy {
+            quota: u64::MAX,
+            ..policy(0, 100)
+        };
+        assert_eq!(p.borrow(), u64::MAX);
+        assert!(p.check().is_ok());
+        assert!(policy(0, 101).check().is_err());
+    }
+}
@@ -1,27 +1,19 @@
-use std::os::fd::AsFd;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::Duration;
 
-use aya::maps::IterableMap;
-use aya::programs::{CgroupSkb, CgroupSkbAttachType};
-use aya::{include_bytes_aligned, Bpf};
-use clap::Parser;
+use bandwidth_limit::schedule::{LocalTime, Rule, Schedule};
+use bandwidth_limit::{
+    flows, pinned_counters, pinned_map, Counters, Direction, Enforce, Limiter, Policy,
+};
+use clap::{Parser, Subcommand};
 use log::{info, warn};
+use serde::Serialize;
 
-#[repr(C)]
-struct Globals {
-    byte_count: AtomicU64,
-    hard_quota: u64,
-}
+use report::{LogFormat, Reporter, Sample};
 
-impl Globals {
-    pub fn byte_count(&self) -> u64 {
-        self.byte_count.load(Ordering::Relaxed)
-    }
-}
-
-mod mmap;
+mod report;
 
 static SIGNAL_PENDING: AtomicBool = AtomicBool::new(false);
 extern "C" fn signal_handler(_signal: i32) {
@@ -29,7 +21,26 @@ extern "C" fn signal_handler(_signal: i32) {
     SIGNAL_PENDING.store(true, Ordering::Relaxed);
 }
 
-#[derive(Debug, Clone, Parser)]
+#[derive(Debug, Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(flatten)]
+    run: Option<Opt>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Print a snapshot of the counters pinned by a running (or stopped) helper, only maps
+    /// pinned with `--pin-path` can be read
+    Status(StatusOpt),
+    /// List the flows that used the most bytes in the current quota period
+    Flows(FlowsOpt),
+}
+
+#[derive(Debug, Clone, clap::Args)]
 struct Opt {
     /// Cgroup to attach to (absolute path)
     #[clap(short, long)]
@@ -46,106 +57,318 @@ struct Opt {
     /// Metrics collection sample interval in number of seconds
     #[clap(short, long)]
     sample_interval: u64,
+
+    /// Output format of the metrics samples
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Directory (on a bpffs mount) to pin the counter maps to, required for `status`
+    #[clap(long)]
+    pin_path: Option<PathBuf>,
+
+    /// Track per-flow counters for the `flows` command (requires --pin-path)
+    #[clap(long, requires = "pin_path")]
+    flows: bool,
+
+    /// How the quota is enforced. `pace` and `ecn` limit the rate to quota / quota period
+    /// before the hard quota is hit
+    #[clap(long, value_enum, default_value_t = EnforceArg::Drop)]
+    enforce: EnforceArg,
+
+    /// Interface to attach the egress pacing program to (required for `--enforce pace`)
+    #[clap(short, long, required_if_eq("enforce", "pace"))]
+    interface: Option<String>,
+
+    /// Rate for `--enforce pace|ecn` in bytes per second. Defaults to quota / quota period
+    #[clap(short, long)]
+    rate: Option<u64>,
+
+    /// Time window with other limits: `[DAYS] HH:MM-HH:MM key=value[,key=value]`,
+    /// e.g. `weekdays 08:00-18:00 rate=2M,enforce=ecn`. Keys are quota, rate and enforce.
+    /// Can be given multiple times, the first matching rule is active
+    #[clap(long)]
+    schedule: Vec<Rule>,
+
+    /// Carry unused bytes over into the next quota period, up to this number of bytes
+    #[clap(long, default_value_t = 0)]
+    rollover_cap: u64,
+
+    /// Allow exceeding the quota by this percentage (at most 100), the excess is deducted from
+    /// the next quota period
+    #[clap(long, default_value_t = 0, value_parser = clap::value_parser!(u64).range(..=100))]
+    borrow: u64,
 }
 
-struct ByteCount(u64);
-impl core::fmt::Display for ByteCount {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.0 < 1024 {
-            write!(f, "{}B", self.0)
-        } else if self.0 < 1024 * 1024 {
-            write!(f, "{:.1}KiB", self.0 as f64 / 1024.)
-        } else if self.0 < 1024 * 1024 * 1024 {
-            write!(f, "{:.1}MiB", self.0 as f64 / (1024. * 1024.))
-        } else {
-            write!(f, "{:.1}GiB", self.0 as f64 / (1024. * 1024. * 1024.))
+impl Opt {
+    fn policy(&self) -> Policy {
+        Policy {
+            quota: self.quota,
+            quota_period: Duration::from_secs(self.quota_period),
+            rate: self.rate,
+            enforce: self.enforce.into(),
+            interface: self.interface.clone(),
+            track_flows: self.flows,
+            rollover_cap: self.rollover_cap,
+            borrow_percent: self.borrow,
         }
     }
 }
 
-fn load_program(opt: &Opt, typ: CgroupSkbAttachType) -> Result<(), anyhow::Error> {
-    let dir = match typ {
-        CgroupSkbAttachType::Ingress => "ingress",
-        CgroupSkbAttachType::Egress => "egress",
-    };
-    let raw = match typ {
-        CgroupSkbAttachType::Ingress => include_bytes_aligned!("../ebpf/ebpf_ingress.o"),
-        CgroupSkbAttachType::Egress => include_bytes_aligned!("../ebpf/ebpf_egress.o"),
-    };
+/// Command line values of `Enforce`, which does not depend on clap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum EnforceArg {
+    /// Drop packets once the quota is exceeded
+    Drop,
+    /// Delay egress packets using earliest departure time (requires the fq qdisc)
+    Pace,
+    /// Mark packets with ECN CE and signal congestion to local senders
+    Ecn,
+}
+
+impl From<EnforceArg> for Enforce {
+    fn from(arg: EnforceArg) -> Self {
+        match arg {
+            EnforceArg::Drop => Enforce::Drop,
+            EnforceArg::Pace => Enforce::Pace,
+            EnforceArg::Ecn => Enforce::Ecn,
+        }
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct StatusOpt {
+    /// Directory the maps were pinned to with `--pin-path`
+    #[clap(long)]
+    pin_path: PathBuf,
+
+    /// Print the snapshot as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct FlowsOpt {
+    /// Directory the maps were pinned to with `--pin-path`
+    #[clap(long)]
+    pin_path: PathBuf,
+
+    /// Number of flows to show
+    #[clap(short, long, default_value_t = 10)]
+    top: usize,
+
+    /// Print the flows as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+/// Applies the policy of the rule active at `now` with `apply`. `active` only changes once the
+/// policy was applied, a failed switch is retried on the next call.
+fn update_schedule<'a>(
+    schedule: &'a Schedule,
+    base: &Policy,
+    now: LocalTime,
+    active: &mut Option<&'a Rule>,
+    apply: impl FnOnce(Policy) -> Result<(), anyhow::Error>,
+) {
+    let rule = schedule.active(now);
+    if rule == *active {
+        return;
+    }
+
+    let policy = rule.map_or_else(|| base.clone(), |r| r.limits.apply(base));
+    if let Err(e) = apply(policy) {
+        warn!("schedule: {:#}", e);
+        return;
+    }
+    match rule {
+        Some(rule) => info!("schedule: switched to '{}'", rule),
+        None => info!("schedule: switched back to the default policy"),
+    }
+    *active = rule;
+}
 
-    let mut bpf = Bpf::load(raw)?;
+fn run(opt: &Opt) -> Result<(), anyhow::Error> {
+    let base = opt.policy();
+    let schedule = Schedule::new(opt.schedule.clone());
+    schedule.check(&base).map_err(anyhow::Error::msg)?;
 
-    let g = aya::maps::Array::<_, u64>::try_from(bpf.take_map("globals").unwrap())?;
-    let program: &mut CgroupSkb = bpf.program_mut("bandwidth_limit").unwrap().try_into()?;
-    let cgroup = std::fs::File::open(&opt.cgroup)?;
-    program.load()?;
-    program.attach(cgroup, typ)?;
+    let mut limiter = Limiter::attach(&opt.cgroup, opt.policy())?;
+    if let Some(pin_path) = &opt.pin_path {
+        limiter.pin(pin_path)?;
+    }
 
-    info!("{} loaded", dir);
+    unsafe { libc::signal(libc::SIGINT, signal_handler as usize) };
 
-    let mut globals = unsafe { mmap::Mmap::<Globals>::new(g.map().fd().as_fd()) }
-        .map_err(|_| anyhow::Error::msg("MAP_FAILED"))?;
+    let mut active = None;
 
-    globals.hard_quota = opt.quota;
     let mut last_reset = std::time::Instant::now();
-    let mut exceeded = false;
+    let mut reporters = Direction::ALL.map(|_| Reporter::new(opt.log_format));
 
     // TODO: persist timers and counters regularly
 
     while !SIGNAL_PENDING.load(Ordering::Relaxed) {
+        if !schedule.is_empty() {
+            update_schedule(&schedule, &base, LocalTime::now(), &mut active, |policy| {
+                limiter.update(policy)
+            });
+        }
+
         let t0 = std::time::Instant::now();
-        let bytes_t0 = globals.byte_count();
+        let usage_t0 = limiter.usage();
 
         thread::sleep(Duration::from_secs(opt.sample_interval));
 
         let dt = t0.elapsed();
-        let bytes = ByteCount(globals.byte_count());
-        let delta = ByteCount((bytes.0 - bytes_t0) / dt.as_secs());
+        let usage = limiter.usage();
 
-        if bytes.0 > opt.quota {
-            if !exceeded {
-                exceeded = true;
-                warn!("{}: {} @ {}/s - 100% of quota exceeded!", dir, bytes, delta);
-            }
-        } else if bytes.0 * 4 > opt.quota * 3 {
-            warn!("{}: {} @ {}/s - 75% of quota exceeded!", dir, bytes, delta);
-        } else if bytes.0 * 2 > opt.quota {
-            warn!("{}: {} @ {}/s - 50% of quota exceeded!", dir, bytes, delta);
-        } else {
-            info!("{}: {} @ {}/s", dir, bytes, delta);
+        for (dir, reporter) in Direction::ALL.into_iter().zip(&mut reporters) {
+            let bytes = usage.get(dir);
+            let rate = bytes.saturating_sub(usage_t0.get(dir)) / dt.as_secs().max(1);
+            reporter.report(&Sample::new(
+                &opt.cgroup,
+                dir.as_str(),
+                bytes,
+                rate,
+                limiter.counters(dir).quota,
+            ));
         }
 
         if last_reset.elapsed().as_secs() > opt.quota_period {
             last_reset = std::time::Instant::now();
-            globals.byte_count.store(0, Ordering::Relaxed);
-            exceeded = false;
+            limiter.reset();
+            reporters.iter_mut().for_each(Reporter::reset);
         }
     }
 
+    limiter.detach();
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct DirectionStatus {
+    direction: Direction,
+    #[serde(flatten)]
+    counters: Counters,
+    percent: Option<f64>,
+}
+
+fn status(opt: &StatusOpt) -> Result<(), anyhow::Error> {
+    let snapshot = Direction::ALL
+        .into_iter()
+        .map(|direction| {
+            let counters = pinned_counters(&opt.pin_path, direction).map_err(|e| {
+                anyhow::anyhow!("{:#} (was the helper started with --pin-path?)", e)
+            })?;
+            Ok(DirectionStatus {
+                direction,
+                counters,
+                percent: report::percent(counters.bytes, counters.quota),
+            })
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+
+    if opt.json {
+        #[derive(Serialize)]
+        struct Status<'a> {
+            timestamp: u64,
+            directions: &'a [DirectionStatus],
+        }
+
+        let status = Status {
+            timestamp: report::unix_time(),
+            directions: &snapshot,
+        };
+        println!("{}", serde_json::to_string(&status)?);
+    } else {
+        for s in &snapshot {
+            let c = &s.counters;
+            match s.percent {
+                Some(percent) => println!(
+                    "{}: {} of {} ({:.1}%), credit {}, debt {}, may borrow {}",
+                    s.direction.as_str(),
+                    report::ByteCount(c.bytes),
+                    report::ByteCount(c.quota),
+                    percent,
+                    report::ByteCount(c.credit),
+                    report::ByteCount(c.debt),
+                    report::ByteCount(c.borrow),
+                ),
+                None => println!(
+                    "{}: {} (unlimited)",
+                    s.direction.as_str(),
+                    report::ByteCount(c.bytes)
+                ),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn list_flows(opt: &FlowsOpt) -> Result<(), anyhow::Error> {
+    let ingress = flows::open_pinned(&pinned_map(&opt.pin_path, Direction::Ingress, "flows"))?;
+    let egress = flows::open_pinned(&pinned_map(&opt.pin_path, Direction::Egress, "flows"))?;
+
+    let top = flows::top_flows(&ingress, &egress, opt.top);
+    if opt.json {
+        println!("{}", serde_json::to_string(&top)?);
+    } else {
+        report::print_flows(&top);
+    }
+
     Ok(())
 }
 
 fn main() {
     env_logger::init();
 
-    let opt = Opt::parse();
+    let cli = Cli::parse();
 
-    let ingress = {
-        let opt = opt.clone();
-        std::thread::spawn(move || {
-            load_program(&opt, CgroupSkbAttachType::Ingress).expect("failed to load ingress");
-        })
+    let (name, result) = match (cli.command, cli.run) {
+        (Some(Command::Status(opt)), _) => ("status", status(&opt)),
+        (Some(Command::Flows(opt)), _) => ("flows", list_flows(&opt)),
+        (None, Some(opt)) => ("run", run(&opt)),
+        (None, None) => {
+            use clap::CommandFactory;
+            Cli::command().print_help().unwrap();
+            std::process::exit(2);
+        }
     };
 
-    let egress = {
-        let opt = opt.clone();
-        std::thread::spawn(move || {
-            load_program(&opt, CgroupSkbAttachType::Egress).expect("failed to load egress");
-        })
-    };
+    if let Err(e) = result {
+        eprintln!("{}: {:#}", name, e);
+        std::process::exit(1);
+    }
+}
 
-    unsafe { libc::signal(libc::SIGINT, signal_handler as usize) };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    ingress.join().unwrap();
-    egress.join().unwrap();
+    #[test]
+    fn schedule_retries_failed_switches() {
+        let base = Policy::new(1 << 20, Duration::from_secs(1));
+        let schedule = Schedule::new(vec!["daily 00:00-12:00 quota=1K".parse().unwrap()]);
+        let now = || LocalTime {
+            weekday: 1,
+            minute: 60,
+        };
+        let mut active = None;
+        let mut applied = Vec::new();
+
+        update_schedule(&schedule, &base, now(), &mut active, |policy| {
+            applied.push(policy.quota);
+            Err(anyhow::anyhow!("busy"))
+        });
+        assert_eq!(active, None);
+
+        for _ in 0..2 {
+            update_schedule(&schedule, &base, now(), &mut active, |policy| {
+                applied.push(policy.quota);
+                Ok(())
+            });
+            assert_eq!(active, schedule.active(now()));
+        }
+        assert_eq!(applied, [1024, 1024]);
+    }
 }
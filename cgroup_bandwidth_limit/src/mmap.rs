@@ -1,11 +1,10 @@
+use std::io;
 use std::marker::PhantomData;
 use std::os::fd::{AsRawFd, BorrowedFd};
 use std::ptr::NonNull;
+use std::sync::atomic::AtomicU64;
 
-pub struct Mmap<'fd, T> {
-    ptr: NonNull<T>,
-    _marker: PhantomData<&'fd ()>,
-}
+use aya::Pod;
 
 macro_rules! align_up {
     ($x:expr, $align:expr) => {
@@ -13,51 +12,287 @@ macro_rules! align_up {
     };
 }
 
-impl<'fd, T> Mmap<'fd, T> {
-    const fn len() -> usize {
-        align_up!(core::mem::size_of::<T>(), 0x1000)
+// see include/uapi/linux/bpf.h
+const BPF_OBJ_GET_INFO_BY_FD: libc::c_long = 15;
+const BPF_MAP_TYPE_ARRAY: u32 = 2;
+const BPF_F_MMAPABLE: u32 = 1 << 10;
+
+/// Leading fields of `struct bpf_map_info`, the kernel only fills in `info_len` bytes.
+#[repr(C)]
+#[derive(Default)]
+struct BpfMapInfo {
+    map_type: u32,
+    id: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+struct BpfInfoAttr {
+    bpf_fd: u32,
+    info_len: u32,
+    info: u64,
+}
+
+fn map_info(fd: BorrowedFd<'_>) -> io::Result<BpfMapInfo> {
+    let mut info = BpfMapInfo::default();
+    let mut attr = BpfInfoAttr {
+        bpf_fd: fd.as_raw_fd() as u32,
+        info_len: core::mem::size_of::<BpfMapInfo>() as u32,
+        info: &mut info as *mut _ as u64,
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_OBJ_GET_INFO_BY_FD,
+            &mut attr as *mut BpfInfoAttr,
+            core::mem::size_of::<BpfInfoAttr>(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
     }
+    Ok(info)
+}
 
-    pub unsafe fn new(fd: BorrowedFd<'fd>) -> Result<Self, ()> {
-        // note that size_of::<*const T>() == size_of::<usize>() because T: Sized
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Checks that the map is a mmapable array with at least `max_entries` values of type `T`.
+fn check_layout<T>(info: &BpfMapInfo, max_entries: u32) -> io::Result<()> {
+    if info.map_type != BPF_MAP_TYPE_ARRAY {
+        return Err(invalid(format!(
+            "map type {} is not an array",
+            info.map_type
+        )));
+    }
+    if info.map_flags & BPF_F_MMAPABLE == 0 {
+        return Err(invalid(
+            "map was not created with BPF_F_MMAPABLE".to_string(),
+        ));
+    }
+    if info.value_size as usize != core::mem::size_of::<T>() {
+        return Err(invalid(format!(
+            "value size {} does not match {}",
+            info.value_size,
+            core::mem::size_of::<T>()
+        )));
+    }
+    if info.max_entries < max_entries {
+        return Err(invalid(format!(
+            "map has {} entries, expected at least {}",
+            info.max_entries, max_entries
+        )));
+    }
+    // values are 8 byte aligned within the map
+    if core::mem::align_of::<T>() > 8 {
+        return Err(invalid("value alignment exceeds 8 bytes".to_string()));
+    }
+    Ok(())
+}
+
+/// Marker for mappings created with `PROT_READ`
+pub enum ReadOnly {}
+/// Marker for mappings created with `PROT_READ | PROT_WRITE`
+pub enum ReadWrite {}
+
+/// Shared mapping of a `BPF_MAP_TYPE_ARRAY` created with `BPF_F_MMAPABLE`.
+///
+/// The values are accessed concurrently by the BPF programs, so there are only volatile and
+/// atomic accessors. The mapping holds its own reference to the map, it stays valid after the
+/// fd is closed.
+pub struct MmapArray<T, A = ReadWrite> {
+    ptr: NonNull<u8>,
+    map_len: usize,
+    stride: usize,
+    max_entries: usize,
+    _marker: PhantomData<(*mut T, A)>,
+}
+
+unsafe impl<T: Pod, A> Send for MmapArray<T, A> {}
+unsafe impl<T: Pod, A> Sync for MmapArray<T, A> {}
+
+impl<T: Pod, A> MmapArray<T, A> {
+    unsafe fn map(fd: BorrowedFd<'_>, max_entries: u32, prot: libc::c_int) -> io::Result<Self> {
+        let info = map_info(fd)?;
+        check_layout::<T>(&info, max_entries)?;
+
+        let page_size = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+        let stride = align_up!(info.value_size as usize, 8);
+        let map_len = align_up!(stride * info.max_entries as usize, page_size);
 
-        let len = Self::len();
         let ptr = libc::mmap(
             core::ptr::null_mut(),
-            len,
-            libc::PROT_READ | libc::PROT_WRITE,
+            map_len,
+            prot,
             libc::MAP_SHARED,
             fd.as_raw_fd(),
             0,
         );
         match ptr {
-            libc::MAP_FAILED => Err(()),
+            libc::MAP_FAILED => Err(io::Error::last_os_error()),
             ptr => Ok(Self {
-                ptr: NonNull::new_unchecked(ptr as *mut T),
+                ptr: NonNull::new_unchecked(ptr as *mut u8),
+                map_len,
+                stride,
+                max_entries: info.max_entries as usize,
                 _marker: PhantomData,
             }),
         }
     }
+
+    pub fn len(&self) -> usize {
+        self.max_entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.max_entries == 0
+    }
+
+    fn slot(&self, index: u32) -> io::Result<*mut T> {
+        let index = index as usize;
+        if index >= self.max_entries {
+            return Err(invalid(format!(
+                "index {} out of bounds, the array has {} entries",
+                index, self.max_entries
+            )));
+        }
+        Ok(unsafe { self.ptr.as_ptr().add(index * self.stride) as *mut T })
+    }
+
+    /// Volatile read of the value at `index`.
+    pub fn get(&self, index: u32) -> io::Result<T> {
+        Ok(unsafe { core::ptr::read_volatile(self.slot(index)?) })
+    }
+}
+
+impl<T: Pod> MmapArray<T, ReadOnly> {
+    /// Map the array read-only, it must have at least `max_entries` entries of type `T`.
+    ///
+    /// # Safety
+    /// `fd` must refer to a BPF map.
+    pub unsafe fn read_only(fd: BorrowedFd<'_>, max_entries: u32) -> io::Result<Self> {
+        Self::map(fd, max_entries, libc::PROT_READ)
+    }
+}
+
+impl<T: Pod> MmapArray<T, ReadWrite> {
+    /// Map the array writable, it must have at least `max_entries` entries of type `T`.
+    ///
+    /// # Safety
+    /// `fd` must refer to a BPF map.
+    pub unsafe fn new(fd: BorrowedFd<'_>, max_entries: u32) -> io::Result<Self> {
+        Self::map(fd, max_entries, libc::PROT_READ | libc::PROT_WRITE)
+    }
+
+    /// Volatile write of the value at `index`.
+    pub fn set(&self, index: u32, value: T) -> io::Result<()> {
+        unsafe { core::ptr::write_volatile(self.slot(index)?, value) };
+        Ok(())
+    }
+}
+
+impl MmapArray<u64, ReadWrite> {
+    /// The value at `index` as an atomic shared with the BPF programs.
+    pub fn atomic(&self, index: u32) -> io::Result<&AtomicU64> {
+        Ok(unsafe { &*(self.slot(index)? as *const AtomicU64) })
+    }
 }
 
-impl<'fd, T> Drop for Mmap<'fd, T> {
+impl<T, A> Drop for MmapArray<T, A> {
     fn drop(&mut self) {
         unsafe {
-            let _error = libc::munmap(self.ptr.as_ptr() as *mut _, Self::len());
+            let _error = libc::munmap(self.ptr.as_ptr() as *mut _, self.map_len);
             // ignore the error
         }
     }
 }
 
-impl<'fd, T> core::ops::Deref for Mmap<'fd, T> {
-    type Target = T;
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
 
-    fn deref(&self) -> &Self::Target {
-        unsafe { self.ptr.as_ref() }
+    use super::*;
+
+    fn array_info() -> BpfMapInfo {
+        BpfMapInfo {
+            map_type: BPF_MAP_TYPE_ARRAY,
+            value_size: 8,
+            max_entries: 8,
+            map_flags: BPF_F_MMAPABLE,
+            ..Default::default()
+        }
     }
-}
-impl<'fd, T> core::ops::DerefMut for Mmap<'fd, T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { self.ptr.as_mut() }
+
+    fn layout_error<T>(info: &BpfMapInfo, max_entries: u32) -> String {
+        check_layout::<T>(info, max_entries)
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn layout() {
+        let info = array_info();
+        assert!(check_layout::<u64>(&info, 8).is_ok());
+        assert!(check_layout::<u64>(&info, 4).is_ok());
+        assert_eq!(
+            layout_error::<u32>(&info, 8),
+            "value size 8 does not match 4"
+        );
+        assert_eq!(
+            layout_error::<u64>(&info, 9),
+            "map has 8 entries, expected at least 9"
+        );
+
+        let info = BpfMapInfo {
+            map_flags: 0,
+            ..array_info()
+        };
+        assert_eq!(
+            layout_error::<u64>(&info, 8),
+            "map was not created with BPF_F_MMAPABLE"
+        );
+        let info = BpfMapInfo {
+            map_type: 1,
+            ..array_info()
+        };
+        assert_eq!(layout_error::<u64>(&info, 8), "map type 1 is not an array");
+    }
+
+    #[test]
+    fn bounds() {
+        // anonymous memory stands in for the map
+        let map_len = 4096;
+        let ptr = unsafe {
+            libc::mmap(
+                core::ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(ptr, libc::MAP_FAILED);
+        let array = MmapArray::<u64> {
+            ptr: NonNull::new(ptr as *mut u8).unwrap(),
+            map_len,
+            stride: 8,
+            max_entries: 4,
+            _marker: PhantomData,
+        };
+
+        array.set(3, 7).unwrap();
+        assert_eq!(array.get(3).unwrap(), 7);
+        assert_eq!(array.atomic(3).unwrap().fetch_add(1, Ordering::Relaxed), 7);
+        assert_eq!(array.get(3).unwrap(), 8);
+
+        let error = array.get(4).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(array.set(4, 0).is_err());
+        assert!(array.atomic(u32::MAX).is_err());
     }
 }
@@ -0,0 +1,151 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use serde::Serialize;
+
+use bandwidth_limit::flows::Flow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human readable log lines
+    Text,
+    /// One JSON object per line on stdout
+    Json,
+}
+
+pub struct ByteCount(pub u64);
+impl core::fmt::Display for ByteCount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0 < 1024 {
+            write!(f, "{}B", self.0)
+        } else if self.0 < 1024 * 1024 {
+            write!(f, "{:.1}KiB", self.0 as f64 / 1024.)
+        } else if self.0 < 1024 * 1024 * 1024 {
+            write!(f, "{:.1}MiB", self.0 as f64 / (1024. * 1024.))
+        } else {
+            write!(f, "{:.1}GiB", self.0 as f64 / (1024. * 1024. * 1024.))
+        }
+    }
+}
+
+/// A single metrics sample of one direction.
+#[derive(Debug, Serialize)]
+pub struct Sample<'a> {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub cgroup: &'a str,
+    pub direction: &'a str,
+    /// Bytes counted in the current quota period
+    pub bytes: u64,
+    /// Average rate over the last sample interval in bytes per second
+    pub rate: u64,
+    /// Quota per period in bytes, 0 means unlimited
+    pub quota: u64,
+    /// Percentage of the quota used, `None` if unlimited
+    pub percent: Option<f64>,
+}
+
+impl<'a> Sample<'a> {
+    pub fn new(cgroup: &'a str, direction: &'a str, bytes: u64, rate: u64, quota: u64) -> Self {
+        Self {
+            timestamp: unix_time(),
+            cgroup,
+            direction,
+            bytes,
+            rate,
+            quota,
+            percent: percent(bytes, quota),
+        }
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn percent(bytes: u64, quota: u64) -> Option<f64> {
+    if quota == 0 {
+        None
+    } else {
+        Some(bytes as f64 * 100. / quota as f64)
+    }
+}
+
+/// Writes samples in the selected format.
+///
+/// The text format only warns once when the quota is exceeded, the json
+/// format emits every sample so consumers always see the full series.
+pub struct Reporter {
+    format: LogFormat,
+    exceeded: bool,
+}
+
+impl Reporter {
+    pub fn new(format: LogFormat) -> Self {
+        Self {
+            format,
+            exceeded: false,
+        }
+    }
+
+    /// Forget about an already reported quota violation, i.e. on period reset.
+    pub fn reset(&mut self) {
+        self.exceeded = false;
+    }
+
+    pub fn report(&mut self, sample: &Sample) {
+        match self.format {
+            LogFormat::Text => self.report_text(sample),
+            LogFormat::Json => match serde_json::to_string(sample) {
+                Ok(line) => println!("{}", line),
+                Err(e) => warn!("failed to serialize sample: {}", e),
+            },
+        }
+    }
+
+    fn report_text(&mut self, sample: &Sample) {
+        let dir = sample.direction;
+        let bytes = ByteCount(sample.bytes);
+        let delta = ByteCount(sample.rate);
+        let quota = sample.quota;
+
+        if quota == 0 {
+            info!("{}: {} @ {}/s", dir, bytes, delta);
+        } else if bytes.0 > quota {
+            if !self.exceeded {
+                self.exceeded = true;
+                warn!("{}: {} @ {}/s - 100% of quota exceeded!", dir, bytes, delta);
+            }
+        } else if bytes.0 * 4 > quota * 3 {
+            warn!("{}: {} @ {}/s - 75% of quota exceeded!", dir, bytes, delta);
+        } else if bytes.0 * 2 > quota {
+            warn!("{}: {} @ {}/s - 50% of quota exceeded!", dir, bytes, delta);
+        } else {
+            info!("{}: {} @ {}/s", dir, bytes, delta);
+        }
+    }
+}
+
+pub fn print_flows(flows: &[Flow]) {
+    println!(
+        "{:<40} {:>6} {:<40} {:>6} {:>8} {:>10} {:>8} {:>10} {:>8}",
+        "REMOTE", "PORT", "LOCAL", "PORT", "PROTO", "RX", "RX DROP", "TX", "TX DROP"
+    );
+    for f in flows {
+        println!(
+            "{:<40} {:>6} {:<40} {:>6} {:>8} {:>10} {:>8} {:>10} {:>8}",
+            f.remote_addr.to_string(),
+            f.remote_port,
+            f.local_addr.to_string(),
+            f.local_port,
+            f.protocol,
+            ByteCount(f.ingress.bytes).to_string(),
+            f.ingress.drops,
+            ByteCount(f.egress.bytes).to_string(),
+            f.egress.drops,
+        );
+    }
+}
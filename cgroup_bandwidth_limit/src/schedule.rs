@@ -0,0 +1,425 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{Enforce, Policy};
+
+/// Overrides of the base policy while a rule is active.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub quota: Option<u64>,
+    pub rate: Option<u64>,
+    pub enforce: Option<Enforce>,
+}
+
+impl Limits {
+    pub fn apply(&self, base: &Policy) -> Policy {
+        Policy {
+            quota: self.quota.unwrap_or(base.quota),
+            rate: self.rate.or(base.rate),
+            enforce: self.enforce.unwrap_or(base.enforce),
+            ..base.clone()
+        }
+    }
+}
+
+/// Day of week and minute of day in local time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    /// 0 = monday
+    pub weekday: u8,
+    pub minute: u16,
+}
+
+impl LocalTime {
+    pub fn now() -> Self {
+        let mut tm: libc::tm = unsafe { core::mem::zeroed() };
+        unsafe {
+            let t = libc::time(core::ptr::null_mut());
+            libc::localtime_r(&t, &mut tm);
+        }
+
+        Self {
+            // tm_wday: 0 = sunday
+            weekday: ((tm.tm_wday + 6) % 7) as u8,
+            minute: (tm.tm_hour * 60 + tm.tm_min) as u16,
+        }
+    }
+}
+
+/// Time window with the limits to use within, e.g. `mon-fri 08:00-18:00 rate=2M`.
+///
+/// Windows ending before they start wrap around midnight, `22:00-06:00` on a monday lasts
+/// until tuesday morning.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    // bit 0 = monday
+    days: u8,
+    start: u16,
+    end: u16,
+    pub limits: Limits,
+    spec: String,
+}
+
+impl Rule {
+    fn on(&self, weekday: u8) -> bool {
+        self.days & (1 << weekday) != 0
+    }
+
+    pub fn matches(&self, t: LocalTime) -> bool {
+        if self.start < self.end {
+            self.on(t.weekday) && t.minute >= self.start && t.minute < self.end
+        } else {
+            let yesterday = (t.weekday + 6) % 7;
+            (self.on(t.weekday) && t.minute >= self.start)
+                || (self.on(yesterday) && t.minute < self.end)
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+fn parse_day(s: &str) -> Result<u8, String> {
+    DAYS.iter()
+        .position(|d| s.eq_ignore_ascii_case(d))
+        .map(|d| d as u8)
+        .ok_or_else(|| format!("invalid day '{}'", s))
+}
+
+/// `daily`, `weekdays`, `weekends` or a comma separated list of days and ranges: `mon-wed,sat`
+fn parse_days(s: &str) -> Result<u8, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "daily" | "*" => return Ok(0x7f),
+        "weekdays" => return Ok(0x1f),
+        "weekends" => return Ok(0x60),
+        _ => {}
+    }
+
+    let mut days = 0u8;
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((from, to)) => {
+                let (from, to) = (parse_day(from)?, parse_day(to)?);
+                let mut d = from;
+                loop {
+                    days |= 1 << d;
+                    if d == to {
+                        break;
+                    }
+                    d = (d + 1) % 7;
+                }
+            }
+            None => days |= 1 << parse_day(part)?,
+        }
+    }
+    Ok(days)
+}
+
+fn parse_minute(s: &str) -> Result<u16, String> {
+    let invalid = || format!("invalid time '{}', expected HH:MM", s);
+    let (h, m) = s.split_once(':').ok_or_else(invalid)?;
+    // single digit minutes like `8:5` are ambiguous
+    let is_number = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    if !is_number(h) || h.len() > 2 || !is_number(m) || m.len() != 2 {
+        return Err(invalid());
+    }
+    let h: u16 = h.parse().map_err(|_| invalid())?;
+    let m: u16 = m.parse().map_err(|_| invalid())?;
+    // 24:00 is allowed as end of the day
+    if m >= 60 || h * 60 + m > 24 * 60 {
+        return Err(invalid());
+    }
+    Ok(h * 60 + m)
+}
+
+/// Number of bytes with an optional binary suffix: `512K`, `2MiB`, `1G`
+pub fn parse_bytes(s: &str) -> Result<u64, String> {
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let factor = match s[digits.len()..].to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        suffix => return Err(format!("invalid size suffix '{}'", suffix)),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(factor))
+        .ok_or_else(|| format!("invalid size '{}'", s))
+}
+
+fn parse_limits(s: &str) -> Result<Limits, String> {
+    let mut limits = Limits::default();
+    for kv in s.split(',') {
+        let (key, value) = kv
+            .split_once('=')
+            .ok_or_else(|| format!("expected key=value, got '{}'", kv))?;
+        match key {
+            "quota" => limits.quota = Some(parse_bytes(value)?),
+            "rate" => limits.rate = Some(parse_bytes(value)?),
+            "enforce" => limits.enforce = Some(value.parse::<Enforce>()?),
+            _ => return Err(format!("unknown setting '{}'", key)),
+        }
+    }
+    Ok(limits)
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    /// `[DAYS] HH:MM-HH:MM key=value[,key=value]` with the keys `quota`, `rate` and `enforce`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = s.split_whitespace().collect();
+        let (days, window, limits) = match parts[..] {
+            [window, limits] => (0x7f, window, limits),
+            [days, window, limits] => (parse_days(days)?, window, limits),
+            _ => return Err("expected '[DAYS] HH:MM-HH:MM key=value[,key=value]'".to_string()),
+        };
+        let (start, end) = window
+            .split_once('-')
+            .ok_or_else(|| format!("invalid time window '{}'", window))?;
+
+        Ok(Self {
+            days,
+            start: parse_minute(start)?,
+            end: parse_minute(end)?,
+            limits: parse_limits(limits)?,
+            spec: parts.join(" "),
+        })
+    }
+}
+
+/// Ordered list of rules, the first matching rule is active.
+#[derive(Debug, Clone, Default)]
+pub struct Schedule {
+    rules: Vec<Rule>,
+}
+
+impl Schedule {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn active(&self, t: LocalTime) -> Option<&Rule> {
+        self.rules.iter().find(|r| r.matches(t))
+    }
+
+    /// Rejects rules which need other programs than the ones attached for `base`, and rules
+    /// with a rate which would be ignored because `Enforce::Drop` only enforces the quota.
+    pub fn check(&self, base: &Policy) -> Result<(), String> {
+        for rule in &self.rules {
+            let enforce = rule.limits.apply(base).enforce;
+            if !base.enforce.can_switch_to(enforce) {
+                return Err(format!(
+                    "rule '{}' cannot switch from enforce={} to enforce={} without reattaching, \
+                     change the rule or the base policy",
+                    rule,
+                    base.enforce.as_str(),
+                    enforce.as_str()
+                ));
+            }
+            if rule.limits.rate.is_some() && enforce == Enforce::Drop {
+                return Err(format!(
+                    "rule '{}' sets a rate which is ignored with enforce=drop, \
+                     add enforce=ecn to the rule or use enforce=ecn or pace for the base policy",
+                    rule
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(s: &str) -> Rule {
+        s.parse().unwrap()
+    }
+
+    fn at(weekday: u8, h: u16, m: u16) -> LocalTime {
+        LocalTime {
+            weekday,
+            minute: h * 60 + m,
+        }
+    }
+
+    #[test]
+    fn matches() {
+        let r = rule("weekdays 08:00-18:00 quota=1");
+        assert!(r.matches(at(0, 8, 0)));
+        assert!(r.matches(at(4, 17, 59)));
+        assert!(!r.matches(at(0, 18, 0)));
+        assert!(!r.matches(at(5, 10, 0)));
+
+        // across midnight, the early hours belong to the previous day
+        let r = rule("mon 22:00-06:00 quota=1");
+        assert!(r.matches(at(0, 22, 0)));
+        assert!(r.matches(at(1, 5, 59)));
+        assert!(!r.matches(at(1, 6, 0)));
+        assert!(!r.matches(at(0, 5, 0)));
+        assert!(!r.matches(at(1, 22, 0)));
+
+        // across the end of the week
+        let r = rule("sun 22:00-06:00 quota=1");
+        assert!(r.matches(at(6, 23, 0)));
+        assert!(r.matches(at(0, 1, 0)));
+        assert!(!r.matches(at(6, 1, 0)));
+
+        let r = rule("00:00-24:00 quota=1");
+        assert!(r.matches(at(3, 0, 0)));
+        assert!(r.matches(at(3, 23, 59)));
+    }
+
+    #[test]
+    fn days() {
+        assert_eq!(parse_days("daily"), Ok(0x7f));
+        assert_eq!(parse_days("Weekends"), Ok(0b110_0000));
+        assert_eq!(parse_days("mon-wed,sat"), Ok(0b010_0111));
+        // ranges wrap around the end of the week
+        assert_eq!(parse_days("fri-mon"), Ok(0b111_0001));
+        assert_eq!(parse_days("sun-sun"), Ok(0b100_0000));
+        assert_eq!(parse_days("mon-xyz"), Err("invalid day 'xyz'".to_string()));
+        assert_eq!(parse_days(""), Err("invalid day ''".to_string()));
+    }
+
+    #[test]
+    fn minutes() {
+        assert_eq!(parse_minute("00:00"), Ok(0));
+        assert_eq!(parse_minute("8:05"), Ok(485));
+        assert_eq!(parse_minute("23:59"), Ok(1439));
+        // end of the day
+        assert_eq!(parse_minute("24:00"), Ok(1440));
+        for s in [
+            "24:01", "25:00", "12:60", "8:5", "+8:00", "0800", "8:005", "",
+        ] {
+            assert_eq!(
+                parse_minute(s),
+                Err(format!("invalid time '{}', expected HH:MM", s))
+            );
+        }
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(parse_bytes("100"), Ok(100));
+        assert_eq!(parse_bytes("100b"), Ok(100));
+        assert_eq!(parse_bytes("512K"), Ok(512 << 10));
+        assert_eq!(parse_bytes("2MiB"), Ok(2 << 20));
+        assert_eq!(parse_bytes("1g"), Ok(1 << 30));
+        assert_eq!(parse_bytes("16383G"), Ok(16383 << 30));
+        assert_eq!(
+            parse_bytes("17179869184G"),
+            Err("invalid size '17179869184G'".to_string())
+        );
+        assert_eq!(
+            parse_bytes("1T"),
+            Err("invalid size suffix 't'".to_string())
+        );
+        assert_eq!(parse_bytes("M"), Err("invalid size 'M'".to_string()));
+        assert_eq!(parse_bytes("1.5M"), Err("invalid size '1.5M'".to_string()));
+    }
+
+    #[test]
+    fn rules() {
+        let r = rule("  fri-mon   22:00-06:00 quota=1K,rate=2M,enforce=ECN");
+        assert_eq!(r.days, 0b111_0001);
+        assert_eq!((r.start, r.end), (22 * 60, 6 * 60));
+        assert_eq!(
+            r.limits,
+            Limits {
+                quota: Some(1024),
+                rate: Some(2 << 20),
+                enforce: Some(Enforce::Ecn),
+            }
+        );
+        assert_eq!(
+            r.to_string(),
+            "fri-mon 22:00-06:00 quota=1K,rate=2M,enforce=ECN"
+        );
+
+        let errors = [
+            (
+                "08:00-18:00",
+                "expected '[DAYS] HH:MM-HH:MM key=value[,key=value]'",
+            ),
+            ("xyz 08:00-18:00 quota=1", "invalid day 'xyz'"),
+            ("0800 quota=1", "invalid time window '0800'"),
+            ("08:00-1800 quota=1", "invalid time '1800', expected HH:MM"),
+            ("08:00-18:00 quota", "expected key=value, got 'quota'"),
+            ("08:00-18:00 speed=1", "unknown setting 'speed'"),
+            ("08:00-18:00 rate=fast", "invalid size suffix 'fast'"),
+            (
+                "08:00-18:00 enforce=slow",
+                "invalid enforcement 'slow', expected drop, pace or ecn",
+            ),
+        ];
+        for (spec, error) in errors {
+            assert_eq!(spec.parse::<Rule>(), Err(error.to_string()), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn check() {
+        let base = |enforce| Policy {
+            enforce,
+            ..Policy::new(1 << 20, std::time::Duration::from_secs(1))
+        };
+        let schedule = |spec: &str| Schedule::new(vec![rule(spec)]);
+
+        let valid = [
+            (Enforce::Drop, "08:00-18:00 quota=1K"),
+            (Enforce::Drop, "08:00-18:00 rate=1K,enforce=ecn"),
+            (Enforce::Ecn, "08:00-18:00 rate=1K"),
+            (Enforce::Ecn, "08:00-18:00 enforce=drop"),
+            (Enforce::Pace, "08:00-18:00 rate=1K"),
+            (Enforce::Pace, "08:00-18:00 quota=1K,enforce=drop"),
+        ];
+        for (enforce, spec) in valid {
+            assert_eq!(schedule(spec).check(&base(enforce)), Ok(()), "{}", spec);
+        }
+
+        let invalid = [
+            (
+                Enforce::Drop,
+                "08:00-18:00 rate=1K",
+                "ignored with enforce=drop",
+            ),
+            (
+                Enforce::Pace,
+                "08:00-18:00 rate=1K,enforce=drop",
+                "ignored with enforce=drop",
+            ),
+            (
+                Enforce::Drop,
+                "08:00-18:00 enforce=pace",
+                "from enforce=drop to enforce=pace",
+            ),
+            (
+                Enforce::Ecn,
+                "08:00-18:00 enforce=pace",
+                "from enforce=ecn to enforce=pace",
+            ),
+            (
+                Enforce::Pace,
+                "08:00-18:00 enforce=ecn",
+                "from enforce=pace to enforce=ecn",
+            ),
+        ];
+        for (enforce, spec, error) in invalid {
+            let result = schedule(spec).check(&base(enforce));
+            assert!(result.as_ref().unwrap_err().contains(error), "{:?}", result);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Tests of the BPF programs against real traffic, ignored by default as they need root:
+//!
+//! ```text
+//! sudo -E cargo test --test integration -- --ignored
+//! ```
+//!
+//! Every test creates a cgroup, a network namespace and a veth pair connecting it to the host.
+//! The test process moves itself into the cgroup to create the limited sockets, their peers live
+//! in the network namespace. No network access is needed.
+
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use bandwidth_limit::{Limiter, Policy};
+
+const HOST_ADDR: Ipv4Addr = Ipv4Addr::new(10, 200, 0, 1);
+const PEER_ADDR: Ipv4Addr = Ipv4Addr::new(10, 200, 0, 2);
+const NETNS: &str = "bwl-test";
+const HOST_LINK: &str = "bwl-host";
+const PEER_LINK: &str = "bwl-peer";
+const CGROUP: &str = "/sys/fs/cgroup/bwl-test";
+
+const DATAGRAM: usize = 1000;
+
+// the tests move the whole process between cgroups and share the interface names
+static LOCK: Mutex<()> = Mutex::new(());
+
+fn ip(args: &[&str]) -> bool {
+    Command::new("ip")
+        .args(args)
+        .status()
+        .expect("failed to run ip")
+        .success()
+}
+
+fn own_cgroup() -> PathBuf {
+    let content = fs::read_to_string("/proc/self/cgroup").unwrap();
+    let path = content
+        .lines()
+        .find_map(|l| l.strip_prefix("0::"))
+        .expect("cgroup v2 is required");
+    PathBuf::from(format!("/sys/fs/cgroup{}", path))
+}
+
+fn move_to(cgroup: &PathBuf) {
+    fs::write(cgroup.join("cgroup.procs"), std::process::id().to_string()).unwrap();
+}
+
+struct Env {
+    cgroup: PathBuf,
+    parent_cgroup: PathBuf,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Env {
+    fn new() -> Self {
+        let lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        // leftovers of an aborted run
+        let _ = fs::remove_dir(CGROUP);
+        ip(&["netns", "del", NETNS]);
+
+        assert!(ip(&["netns", "add", NETNS]));
+        assert!(ip(&[
+            "link", "add", HOST_LINK, "type", "veth", "peer", "name", PEER_LINK, "netns", NETNS
+        ]));
+        assert!(ip(&[
+            "addr",
+            "add",
+            &format!("{}/24", HOST_ADDR),
+            "dev",
+            HOST_LINK
+        ]));
+        assert!(ip(&["link", "set", HOST_LINK, "up"]));
+        assert!(ip(&[
+            "-n",
+            NETNS,
+            "addr",
+            "add",
+            &format!("{}/24", PEER_ADDR),
+            "dev",
+            PEER_LINK
+        ]));
+        assert!(ip(&["-n", NETNS, "link", "set", PEER_LINK, "up"]));
+        assert!(ip(&["-n", NETNS, "link", "set", "lo", "up"]));
+
+        fs::create_dir(CGROUP).unwrap();
+
+        Self {
+            cgroup: PathBuf::from(CGROUP),
+            parent_cgroup: own_cgroup(),
+            _lock: lock,
+        }
+    }
+
+    fn limiter(&self, quota: u64) -> Limiter {
+        Limiter::attach(&self.cgroup, Policy::new(quota, Duration::from_secs(3600))).unwrap()
+    }
+
+    /// Run `f` inside the network namespace, sockets created there stay in it. They have to be
+    /// created outside of the limited cgroup, otherwise they would be counted as well.
+    fn peer<T: Send>(&self, f: impl FnOnce() -> T + Send) -> T {
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                let ns = fs::File::open(format!("/var/run/netns/{}", NETNS)).unwrap();
+                let ret = unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNET) };
+                assert_eq!(ret, 0, "setns: {}", std::io::Error::last_os_error());
+                f()
+            })
+            .join()
+            .unwrap()
+        })
+    }
+
+    /// Run `f` with the process moved into the limited cgroup.
+    fn limited<T>(&self, f: impl FnOnce() -> T) -> T {
+        move_to(&self.cgroup);
+        let ret = f();
+        move_to(&self.parent_cgroup);
+        ret
+    }
+}
+
+impl Drop for Env {
+    fn drop(&mut self) {
+        move_to(&self.parent_cgroup);
+        let _ = fs::remove_dir(&self.cgroup);
+        // removes the veth pair as well
+        ip(&["netns", "del", NETNS]);
+    }
+}
+
+fn udp_peer(env: &Env) -> UdpSocket {
+    env.peer(|| {
+        let socket = UdpSocket::bind((PEER_ADDR, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        socket
+    })
+}
+
+/// Send `n` datagrams, returns the number of datagrams sent successfully.
+fn send_datagrams(socket: &UdpSocket, to: SocketAddr, n: usize) -> usize {
+    let buf = [0xaa; DATAGRAM];
+    (0..n).filter(|_| socket.send_to(&buf, to).is_ok()).count()
+}
+
+fn receive_datagrams(socket: &UdpSocket) -> usize {
+    let mut buf = [0; DATAGRAM];
+    let mut n = 0;
+    loop {
+        match socket.recv(&mut buf) {
+            Ok(_) => n += 1,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+            Err(e) => panic!("recv: {}", e),
+        }
+    }
+    n
+}
+
+#[test]
+#[ignore = "requires root"]
+fn counts_tcp_bytes() {
+    const LEN: usize = 64 * 1024;
+
+    let env = Env::new();
+    let limiter = env.limiter(0);
+    let listener = env.peer(|| TcpListener::bind((PEER_ADDR, 0)).unwrap());
+    let addr = listener.local_addr().unwrap();
+
+    let mut stream = env.limited(|| TcpStream::connect(addr).unwrap());
+    let (mut conn, _) = listener.accept().unwrap();
+
+    stream.write_all(&[0x55; LEN]).unwrap();
+    drop(stream);
+    let mut received = Vec::new();
+    conn.read_to_end(&mut received).unwrap();
+    assert_eq!(received.len(), LEN);
+
+    let usage = limiter.usage();
+    assert!(usage.egress >= LEN as u64, "{:?}", usage);
+    // handshake and acks
+    assert!(usage.ingress > 0, "{:?}", usage);
+}
+
+#[test]
+#[ignore = "requires root"]
+fn drops_after_quota() {
+    const QUOTA: u64 = 8 * 1024;
+
+    let env = Env::new();
+    let limiter = env.limiter(QUOTA);
+    let peer = udp_peer(&env);
+    let to = peer.local_addr().unwrap();
+
+    let socket = env.limited(|| UdpSocket::bind((HOST_ADDR, 0)).unwrap());
+    let sent = send_datagrams(&socket, to, 100);
+    let received = receive_datagrams(&peer);
+
+    // the packet exceeding the quota still passes
+    let max = QUOTA as usize / DATAGRAM + 1;
+    assert!(received <= max, "received {} datagrams", received);
+    assert!(received > 0);
+    // dropped on egress, the sender sees EPERM
+    assert!(sent < 100);
+    let usage = limiter.usage();
+    assert!(usage.egress >= QUOTA, "{:?}", usage);
+}
+
+#[test]
+#[ignore = "requires root"]
+fn drops_ingress_after_quota() {
+    const QUOTA: u64 = 8 * 1024;
+
+    let env = Env::new();
+    let limiter = env.limiter(QUOTA);
+    let peer = env.peer(|| UdpSocket::bind((PEER_ADDR, 0)).unwrap());
+
+    let socket = env.limited(|| UdpSocket::bind((HOST_ADDR, 0)).unwrap());
+    socket
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let sent = send_datagrams(&peer, socket.local_addr().unwrap(), 100);
+    assert_eq!(sent, 100);
+    let received = receive_datagrams(&socket);
+
+    assert!(
+        received <= QUOTA as usize / DATAGRAM + 1,
+        "received {}",
+        received
+    );
+    assert!(limiter.usage().ingress >= QUOTA);
+}
+
+#[test]
+#[ignore = "requires root"]
+fn loopback_is_exempt() {
+    let env = Env::new();
+    let limiter = env.limiter(1024);
+
+    let (socket, peer) = env.limited(|| {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let peer = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        (socket, peer)
+    });
+    peer.set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+
+    let sent = send_datagrams(&socket, peer.local_addr().unwrap(), 50);
+    assert_eq!(sent, 50);
+    assert_eq!(receive_datagrams(&peer), 50);
+
+    let usage = limiter.usage();
+    assert_eq!(usage.ingress, 0);
+    assert_eq!(usage.egress, 0);
+}
+
+#[test]
+#[ignore = "requires root"]
+fn reset_starts_new_period() {
+    const QUOTA: u64 = 4 * 1024;
+
+    let env = Env::new();
+    let mut limiter = env.limiter(QUOTA);
+    let peer = udp_peer(&env);
+    let to = peer.local_addr().unwrap();
+    let socket = env.limited(|| UdpSocket::bind((HOST_ADDR, 0)).unwrap());
+
+    send_datagrams(&socket, to, 20);
+    receive_datagrams(&peer);
+    assert_eq!(send_datagrams(&socket, to, 1), 0, "quota not exceeded");
+
+    limiter.reset();
+    assert_eq!(limiter.usage().egress, 0);
+
+    assert_eq!(send_datagrams(&socket, to, 1), 1);
+    assert_eq!(receive_datagrams(&peer), 1);
+}
@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::vm::MEMORY_SIZE;
+
+/// Instructions which take a jump target (or procedure) as argument.
+const JUMPS: [&str; 4] = ["JMP", "JZ", "JNZ", "CALL"];
+/// Instructions which take a memory address as argument.
+const ACCESSES: [&str; 2] = ["STORE", "FETCH"];
+
+#[derive(Debug)]
+pub enum AsmErrorKind {
+    UndefinedLabel {
+        label: String,
+    },
+    DuplicateLabel {
+        label: String,
+        first: usize,
+    },
+    BadLabelName {
+        label: String,
+    },
+    UndefinedSlot {
+        slot: String,
+    },
+    DuplicateSlot {
+        slot: String,
+        first: usize,
+    },
+    BadSlot {
+        spec: String,
+    },
+    SlotOffset {
+        slot: String,
+        offset: usize,
+        size: usize,
+    },
+    OutOfMemory {
+        slot: String,
+    },
+    BadString {
+        message: String,
+    },
+    BadHostName {
+        name: String,
+    },
+}
+
+/// An error at `line` and `col` of the source file (both starting at 1).
+#[derive(Debug)]
+pub struct AsmError {
+    pub line: usize,
+    pub col: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmErrorKind::UndefinedLabel { label } => write!(f, "undefined label '{}'", label),
+            AsmErrorKind::DuplicateLabel { label, first } => write!(
+                f,
+                "duplicate label '{}' (first defined at line {})",
+                label, first
+            ),
+            AsmErrorKind::BadLabelName { label } => write!(f, "invalid label name '{}'", label),
+            AsmErrorKind::UndefinedSlot { slot } => write!(f, "undefined slot '{}'", slot),
+            AsmErrorKind::DuplicateSlot { slot, first } => write!(
+                f,
+                "duplicate slot '{}' (first declared at line {})",
+                slot, first
+            ),
+            AsmErrorKind::BadSlot { spec } => write!(
+                f,
+                "invalid slot declaration '{}', expected 'SLOT name [size]'",
+                spec
+            ),
+            AsmErrorKind::SlotOffset { slot, offset, size } => write!(
+                f,
+                "offset {} is outside of slot '{}' with {} cells",
+                offset, slot, size
+            ),
+            AsmErrorKind::OutOfMemory { slot } => write!(
+                f,
+                "slot '{}' does not fit into the {} memory cells",
+                slot, MEMORY_SIZE
+            ),
+            AsmErrorKind::BadString { message } => f.write_str(message),
+            AsmErrorKind::BadHostName { name } => {
+                write!(f, "invalid host function name '{}'", name)
+            }
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.col, self.kind)
+    }
+}
+
+/// Named memory cells declared with `SLOT name [size]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Slot {
+    pub name: String,
+    pub addr: usize,
+    pub size: usize,
+}
+
+/// Assembled program, `lines[ip]` is the source line (starting at 1) of the instruction at `ip`.
+pub struct Program {
+    pub instructions: Vec<String>,
+    pub lines: Vec<usize>,
+    /// Label definitions with their instruction index, in source order.
+    pub labels: Vec<(String, usize)>,
+    /// Slots in declaration order, their addresses are allocated consecutively from 0.
+    pub slots: Vec<Slot>,
+    /// String literals of `PRINTS`, each terminated by a NUL byte.
+    pub data: Vec<u8>,
+    /// Names of the host functions, `HOST name` refers to them by index.
+    pub hosts: Vec<String>,
+}
+
+/// Splits the program into lines, everything but string literals is case insensitive.
+pub fn read_program(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(|line| {
+            let mut quoted = false;
+            let mut escaped = false;
+            line.chars()
+                .map(|c| {
+                    let upper = if quoted { c } else { c.to_ascii_uppercase() };
+                    if quoted && c == '\\' && !escaped {
+                        escaped = true;
+                    } else {
+                        quoted ^= c == '"' && !escaped;
+                        escaped = false;
+                    }
+                    upper
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Cuts off a `;` comment, a semicolon within a string literal does not start one.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            ';' if !quoted => return &line[..idx],
+            '\\' if quoted && !escaped => {
+                escaped = true;
+                continue;
+            }
+            '"' if !escaped => quoted = !quoted,
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+/// Column (starting at 1) of `part`, which is a slice of `line`.
+fn column(line: &str, part: &str) -> usize {
+    let offset = part.as_ptr() as usize - line.as_ptr() as usize;
+    line[..offset].chars().count() + 1
+}
+
+/// Column (starting at 1) of the `token`th whitespace separated token of the instruction on
+/// the source `line`, the opcode is token 0. Points just past the instruction if it has fewer
+/// tokens.
+pub fn token_column(line: &str, token: usize) -> usize {
+    let (_, code) = split_label(strip_comment(line));
+    match code.split_ascii_whitespace().nth(token) {
+        Some(part) => column(line, part),
+        None => column(line, &code[code.trim_end().len()..]),
+    }
+}
+
+/// Splits off a leading `label:`, returns the label (if any) and the rest of the line.
+fn split_label(line: &str) -> (Option<&str>, &str) {
+    let trimmed = line.trim_start();
+    // a colon within a string literal does not end a label
+    let code = &trimmed[..trimmed.find('"').unwrap_or(trimmed.len())];
+    match code.find(':') {
+        Some(idx) => (Some(trimmed[..idx].trim()), &trimmed[idx + 1..]),
+        None => (None, line),
+    }
+}
+
+/// Parses a double quoted string literal with the escapes `\n`, `\t`, `\r`, `\\` and `\"`.
+pub fn parse_string(literal: &str) -> Result<String, String> {
+    let inner = literal
+        .strip_prefix('"')
+        .and_then(|l| l.strip_suffix('"'))
+        .filter(|_| literal.len() >= 2)
+        .ok_or_else(|| format!("invalid string literal {}", literal))?;
+
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some('r') => out.push('\r'),
+                Some('\\') => out.push('\\'),
+                Some('"') => out.push('"'),
+                Some(c) => return Err(format!("unknown escape sequence '\\{}'", c)),
+                None => return Err("string literal ends with a backslash".to_string()),
+            },
+            '"' => return Err(format!("unescaped quote in string literal {}", literal)),
+            '\0' => return Err("string literals must not contain NUL".to_string()),
+            c => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+/// Inverse of `parse_string`.
+pub fn quote_string(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Parses `name [size]` of a slot declaration.
+fn parse_slot(spec: &str) -> Option<(&str, usize)> {
+    let parts: Vec<&str> = spec.split_ascii_whitespace().collect();
+    let (name, size) = match parts[..] {
+        [name] => (name, 1),
+        [name, size] => (name, size.parse().ok()?),
+        _ => return None,
+    };
+    if is_label_name(name) && size > 0 {
+        Some((name, size))
+    } else {
+        None
+    }
+}
+
+/// Resolves a memory operand `name` or `name+offset` to an address.
+fn resolve_slot(
+    slots: &HashMap<String, (Slot, usize)>,
+    operand: &str,
+) -> Result<usize, AsmErrorKind> {
+    let (name, offset) = match operand.split_once('+') {
+        Some((name, offset)) => match offset.trim().parse() {
+            Ok(offset) => (name.trim(), offset),
+            Err(_) => {
+                return Err(AsmErrorKind::UndefinedSlot {
+                    slot: operand.to_string(),
+                })
+            }
+        },
+        None => (operand, 0),
+    };
+    match slots.get(name) {
+        Some((slot, _)) if offset < slot.size => Ok(slot.addr + offset),
+        Some((slot, _)) => Err(AsmErrorKind::SlotOffset {
+            slot: name.to_string(),
+            offset,
+            size: slot.size,
+        }),
+        None => Err(AsmErrorKind::UndefinedSlot {
+            slot: name.to_string(),
+        }),
+    }
+}
+
+/// Resolves `label:` definitions and symbolic jump targets (`JNZ loop`, `CALL proc`) to
+/// instruction indices, and `SLOT name [size]` declarations and symbolic memory operands
+/// (`STORE name+1`) to addresses. `HOST name` refers to the table of host function names.
+///
+/// Label definitions may stand on their own line or prefix an instruction. `;` starts a comment
+/// running to the end of the line, blank lines are skipped. Numeric jump targets and addresses
+/// are passed through unchanged. All errors are collected and reported together with their
+/// line and column in the source file.
+pub fn assemble(source: &[String]) -> Result<Program, Vec<AsmError>> {
+    let mut errors = Vec::new();
+    let mut labels: HashMap<String, (usize, usize)> = HashMap::new();
+    let mut slots: HashMap<String, (Slot, usize)> = HashMap::new();
+    let mut next_addr = 0;
+    let mut data: Vec<u8> = Vec::new();
+    let mut strings: HashMap<String, usize> = HashMap::new();
+    let mut hosts: Vec<String> = Vec::new();
+    let mut program: Vec<(usize, String)> = Vec::new();
+
+    // first pass: collect the labels and the instructions
+    for (idx, line) in source.iter().enumerate() {
+        let line_no = idx + 1;
+        let (label, rest) = split_label(strip_comment(line));
+        // errors in the operand of a declaration or instruction
+        let operand_error = |kind| AsmError {
+            line: line_no,
+            col: token_column(line, 1),
+            kind,
+        };
+
+        if let Some(label) = label {
+            let label_error = |kind| AsmError {
+                line: line_no,
+                col: column(line, label),
+                kind,
+            };
+            if !is_label_name(label) {
+                errors.push(label_error(AsmErrorKind::BadLabelName {
+                    label: label.to_string(),
+                }));
+            } else if let Some(&(_, first)) = labels.get(label) {
+                errors.push(label_error(AsmErrorKind::DuplicateLabel {
+                    label: label.to_string(),
+                    first,
+                }));
+            } else {
+                labels.insert(label.to_string(), (program.len(), line_no));
+            }
+        }
+
+        if let Some(spec) = rest.trim().strip_prefix("SLOT ") {
+            match parse_slot(spec) {
+                None => errors.push(operand_error(AsmErrorKind::BadSlot {
+                    spec: spec.trim().to_string(),
+                })),
+                Some((name, _)) if slots.contains_key(name) => {
+                    errors.push(operand_error(AsmErrorKind::DuplicateSlot {
+                        slot: name.to_string(),
+                        first: slots[name].1,
+                    }))
+                }
+                Some((name, size)) if next_addr + size > MEMORY_SIZE => {
+                    errors.push(operand_error(AsmErrorKind::OutOfMemory {
+                        slot: name.to_string(),
+                    }))
+                }
+                Some((name, size)) => {
+                    let slot = Slot {
+                        name: name.to_string(),
+                        addr: next_addr,
+                        size,
+                    };
+                    next_addr += size;
+                    slots.insert(name.to_string(), (slot, line_no));
+                }
+            }
+        } else if let Some(literal) = rest.trim().strip_prefix("PRINTS ").map(str::trim) {
+            if literal.starts_with('"') {
+                match parse_string(literal) {
+                    Ok(text) => {
+                        let offset = *strings.entry(text.clone()).or_insert_with(|| {
+                            data.extend_from_slice(text.as_bytes());
+                            data.push(0);
+                            data.len() - text.len() - 1
+                        });
+                        program.push((line_no, format!("PRINTS {}", offset)));
+                    }
+                    Err(message) => errors.push(operand_error(AsmErrorKind::BadString { message })),
+                }
+            } else {
+                program.push((line_no, rest.trim().to_string()));
+            }
+        } else if let Some(name) = rest.trim().strip_prefix("HOST ").map(str::trim) {
+            if name.parse::<usize>().is_ok() {
+                program.push((line_no, rest.trim().to_string()));
+            } else if is_label_name(name) {
+                let index = match hosts.iter().position(|host| host == name) {
+                    Some(index) => index,
+                    None => {
+                        hosts.push(name.to_string());
+                        hosts.len() - 1
+                    }
+                };
+                program.push((line_no, format!("HOST {}", index)));
+            } else {
+                errors.push(operand_error(AsmErrorKind::BadHostName {
+                    name: name.to_string(),
+                }));
+            }
+        } else if !rest.trim().is_empty() {
+            program.push((line_no, rest.trim().to_string()));
+        }
+    }
+
+    // second pass: replace symbolic jump targets and memory operands
+    let (lines, instructions): (Vec<usize>, Vec<String>) = program.into_iter().unzip();
+    let instructions = instructions
+        .into_iter()
+        .zip(&lines)
+        .map(|(instruction, &line_no)| {
+            let parts: Vec<&str> = instruction.split_ascii_whitespace().collect();
+            match parts.as_slice() {
+                [op, target, rest @ ..]
+                    if JUMPS.contains(op) && target.parse::<usize>().is_err() =>
+                {
+                    match labels.get(*target) {
+                        Some(&(ip, _)) => {
+                            let mut resolved = vec![op.to_string(), ip.to_string()];
+                            resolved.extend(rest.iter().map(|s| s.to_string()));
+                            resolved.join(" ")
+                        }
+                        None => {
+                            errors.push(AsmError {
+                                line: line_no,
+                                col: token_column(&source[line_no - 1], 1),
+                                kind: AsmErrorKind::UndefinedLabel {
+                                    label: target.to_string(),
+                                },
+                            });
+                            instruction
+                        }
+                    }
+                }
+                [op, operand, rest @ ..]
+                    if ACCESSES.contains(op) && operand.parse::<usize>().is_err() =>
+                {
+                    match resolve_slot(&slots, operand) {
+                        Ok(addr) => {
+                            let mut resolved = vec![op.to_string(), addr.to_string()];
+                            resolved.extend(rest.iter().map(|s| s.to_string()));
+                            resolved.join(" ")
+                        }
+                        Err(kind) => {
+                            errors.push(AsmError {
+                                line: line_no,
+                                col: token_column(&source[line_no - 1], 1),
+                                kind,
+                            });
+                            instruction
+                        }
+                    }
+                }
+                _ => instruction,
+            }
+        })
+        .collect();
+
+    if errors.is_empty() {
+        let mut labels: Vec<_> = labels.into_iter().collect();
+        labels.sort_by_key(|&(_, (_, line))| line);
+        let mut slots: Vec<Slot> = slots.into_values().map(|(slot, _)| slot).collect();
+        slots.sort_by_key(|slot| slot.addr);
+        Ok(Program {
+            instructions,
+            lines,
+            labels: labels
+                .into_iter()
+                .map(|(label, (ip, _))| (label, ip))
+                .collect(),
+            slots,
+            data,
+            hosts,
+        })
+    } else {
+        errors.sort_by_key(|error| (error.line, error.col));
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::VmError;
+    use crate::instr::{self, Instr};
+
+    #[test]
+    fn comments_and_blank_lines() {
+        let source =
+            "; a comment\n\nLOAD 2 ; two\n  \nloop: ; the loop\nPRINTS \"a;b\" ; not \"this\"";
+        let program = assemble(&read_program(source)).unwrap();
+        assert_eq!(program.instructions, ["LOAD 2", "PRINTS 0"]);
+        assert_eq!(program.lines, [3, 6]);
+        assert_eq!(program.labels, [("LOOP".to_string(), 1)]);
+        assert_eq!(program.data, b"a;b\0");
+    }
+
+    #[test]
+    fn error_positions() {
+        let source = "\n  JMP nowhere ; x\n\n1x: STORE a+1\nSLOT a";
+        let errors = assemble(&read_program(source)).err().unwrap();
+        let positions: Vec<_> = errors
+            .iter()
+            .map(|error| (error.line, error.col, error.kind.to_string()))
+            .collect();
+        assert_eq!(
+            positions,
+            [
+                (2, 7, "undefined label 'NOWHERE'".to_string()),
+                (4, 1, "invalid label name '1X'".to_string()),
+                (
+                    4,
+                    11,
+                    "offset 1 is outside of slot 'A' with 1 cells".to_string()
+                ),
+            ]
+        );
+
+        assert_eq!(token_column("a: LOAD  1 2 ; 3", 0), 4);
+        assert_eq!(token_column("a: LOAD  1 2 ; 3", 2), 12);
+        assert_eq!(token_column("  LOAD ; 1", 1), 7);
+    }
+
+    #[test]
+    fn operand_count() {
+        let program = assemble(&read_program("LOAD 1\nJMP 0 ; 1")).unwrap();
+        assert_eq!(
+            instr::parse(&program.instructions),
+            Ok(vec![Instr::Load(1.into()), Instr::Jmp(0)])
+        );
+        let extra = |line: &str| instr::parse(&[line.to_string()]).unwrap_err();
+        assert!(matches!(
+            extra("PUSH 3"),
+            VmError::UnexpectedOperand { expected: 0, .. }
+        ));
+        assert!(matches!(
+            extra("JMP 0 1"),
+            VmError::UnexpectedOperand { expected: 1, .. }
+        ));
+    }
+}
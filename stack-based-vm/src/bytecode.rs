@@ -0,0 +1,521 @@
+//! Binary program format, all integers are little endian:
+//!
+//! ```text
+//! magic       b"SVMB"
+//! version     u16
+//! constants   u32 count, (u8 type, 8 bytes value)    operands of LOAD and SET, i32 values
+//!                                                     before version 5
+//! labels      u32 count, (u32 ip, u32 len, name)
+//! slots       u32 count, (u32 addr, u32 size, u32 len, name)     since version 2
+//! data        u32 len, bytes                                      since version 3
+//! hosts       u32 count, (u32 len, name)                          since version 4
+//! code        u32 count, (u8 opcode[, u32 operand])
+//! ```
+//!
+//! `LOAD` and `SET` refer to the constant pool by index, jumps and calls carry the target instruction
+//! index, memory accesses the address, `PRINTS` the offset of its string in the data segment
+//! and `HOST` the index of its name in the hosts table. The labels and slots are only used to restore symbolic
+//! operands when disassembling.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::assembler::{quote_string, Slot};
+use crate::instr::Instr;
+use crate::value::Value;
+
+pub const MAGIC: [u8; 4] = *b"SVMB";
+pub const VERSION: u16 = 5;
+
+#[derive(Debug, PartialEq)]
+pub enum BytecodeError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    BadOpcode { ip: usize, opcode: u8 },
+    BadConstant { ip: usize, index: u32 },
+    BadConstantType { index: usize, tag: u8 },
+    BadLabel { index: usize },
+    BadSlot { index: usize },
+    BadHostName { index: usize },
+    BadHost { ip: usize, index: u32 },
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BytecodeError::BadMagic => write!(f, "not a bytecode file"),
+            BytecodeError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "unsupported bytecode version {} (supported up to {})",
+                    version, VERSION
+                )
+            }
+            BytecodeError::Truncated => write!(f, "unexpected end of file"),
+            BytecodeError::BadOpcode { ip, opcode } => {
+                write!(f, "[{}] unknown opcode 0x{:02x}", ip, opcode)
+            }
+            BytecodeError::BadConstant { ip, index } => {
+                write!(f, "[{}] constant {} is not in the pool", ip, index)
+            }
+            BytecodeError::BadConstantType { index, tag } => {
+                write!(f, "constant {} has the unknown type {}", index, tag)
+            }
+            BytecodeError::BadLabel { index } => write!(f, "label {} is malformed", index),
+            BytecodeError::BadSlot { index } => write!(f, "slot {} is malformed", index),
+            BytecodeError::BadHostName { index } => {
+                write!(f, "host function name {} is malformed", index)
+            }
+            BytecodeError::BadHost { ip, index } => {
+                write!(f, "[{}] host function {} is not in the table", ip, index)
+            }
+            BytecodeError::TrailingBytes(n) => write!(f, "{} unexpected bytes after the code", n),
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {}
+
+/// A decoded bytecode file.
+pub struct Module {
+    pub code: Vec<Instr>,
+    pub labels: Vec<(String, usize)>,
+    pub slots: Vec<Slot>,
+    pub data: Vec<u8>,
+    pub hosts: Vec<String>,
+}
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+fn opcode(instr: &Instr) -> u8 {
+    match instr {
+        Instr::Print => 0x00,
+        Instr::Push => 0x01,
+        Instr::Pop => 0x02,
+        Instr::Top => 0x03,
+        Instr::Add => 0x04,
+        Instr::Sub => 0x05,
+        Instr::Div => 0x06,
+        Instr::Mul => 0x07,
+        Instr::Load(_) => 0x08,
+        Instr::Jmp(_) => 0x09,
+        Instr::Jz(_) => 0x0a,
+        Instr::Jnz(_) => 0x0b,
+        Instr::Store(_) => 0x0c,
+        Instr::Fetch(_) => 0x0d,
+        Instr::Dup => 0x0e,
+        Instr::Swap => 0x0f,
+        Instr::Over => 0x10,
+        Instr::Call(_) => 0x11,
+        Instr::Ret => 0x12,
+        Instr::Read => 0x13,
+        Instr::PrintC => 0x14,
+        Instr::PrintS(_) => 0x15,
+        Instr::Set(_) => 0x16,
+        Instr::Host(_) => 0x17,
+        Instr::IntToFloat => 0x18,
+        Instr::FloatToInt => 0x19,
+        Instr::Mod => 0x1a,
+        Instr::Neg => 0x1b,
+        Instr::Eq => 0x1c,
+        Instr::Lt => 0x1d,
+        Instr::Gt => 0x1e,
+        Instr::And => 0x1f,
+        Instr::Or => 0x20,
+        Instr::Xor => 0x21,
+        Instr::Not => 0x22,
+        Instr::Shl => 0x23,
+        Instr::Shr => 0x24,
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+pub fn encode(
+    code: &[Instr],
+    labels: &[(String, usize)],
+    slots: &[Slot],
+    data: &[u8],
+    hosts: &[String],
+) -> Vec<u8> {
+    let mut constants: Vec<(u8, u64)> = Vec::new();
+    let mut index: HashMap<(u8, u64), usize> = HashMap::new();
+    let mut code_section = Vec::new();
+
+    for instr in code {
+        code_section.push(opcode(instr));
+        match *instr {
+            Instr::Load(value) | Instr::Set(value) => {
+                let constant = match value {
+                    Value::Int(n) => (0, n as u64),
+                    Value::Float(x) => (1, x.to_bits()),
+                };
+                let idx = *index.entry(constant).or_insert_with(|| {
+                    constants.push(constant);
+                    constants.len() - 1
+                });
+                put_u32(&mut code_section, idx);
+            }
+            Instr::Jmp(target) | Instr::Jz(target) | Instr::Jnz(target) | Instr::Call(target) => {
+                put_u32(&mut code_section, target)
+            }
+            Instr::Store(addr) | Instr::Fetch(addr) | Instr::PrintS(addr) | Instr::Host(addr) => {
+                put_u32(&mut code_section, addr)
+            }
+            _ => {}
+        }
+    }
+
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    put_u32(&mut out, constants.len());
+    for (tag, bits) in constants {
+        out.push(tag);
+        out.extend_from_slice(&bits.to_le_bytes());
+    }
+    put_u32(&mut out, labels.len());
+    for (name, ip) in labels {
+        put_u32(&mut out, *ip);
+        put_u32(&mut out, name.len());
+        out.extend_from_slice(name.as_bytes());
+    }
+    put_u32(&mut out, slots.len());
+    for slot in slots {
+        put_u32(&mut out, slot.addr);
+        put_u32(&mut out, slot.size);
+        put_u32(&mut out, slot.name.len());
+        out.extend_from_slice(slot.name.as_bytes());
+    }
+    put_u32(&mut out, data.len());
+    out.extend_from_slice(data);
+    put_u32(&mut out, hosts.len());
+    for name in hosts {
+        put_u32(&mut out, name.len());
+        out.extend_from_slice(name.as_bytes());
+    }
+    put_u32(&mut out, code.len());
+    out.extend_from_slice(&code_section);
+    out
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], BytecodeError> {
+        if self.bytes.len() < n {
+            return Err(BytecodeError::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, BytecodeError> {
+        let mut buf = [0; 2];
+        buf.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    fn u32(&mut self) -> Result<u32, BytecodeError> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Result<u64, BytecodeError> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// Length prefixed UTF-8 string, `Err(None)` if it is not valid UTF-8.
+    fn string(&mut self) -> Result<String, Option<BytecodeError>> {
+        let len = self.u32().map_err(Some)? as usize;
+        let bytes = self.take(len).map_err(Some)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| None)
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Module, BytecodeError> {
+    let mut r = Reader { bytes };
+    if r.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(BytecodeError::BadMagic);
+    }
+    let version = r.u16()?;
+    if version == 0 || version > VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+
+    let n_constants = r.u32()?;
+    let mut constants = Vec::new();
+    for index in 0..n_constants as usize {
+        let value = if version < 5 {
+            Value::Int(i64::from(r.u32()? as i32))
+        } else {
+            match r.u8()? {
+                0 => Value::Int(r.u64()? as i64),
+                1 => Value::Float(f64::from_bits(r.u64()?)),
+                tag => return Err(BytecodeError::BadConstantType { index, tag }),
+            }
+        };
+        constants.push(value);
+    }
+
+    let n_labels = r.u32()?;
+    let mut labels = Vec::new();
+    for index in 0..n_labels as usize {
+        let ip = r.u32()? as usize;
+        let name = r
+            .string()
+            .map_err(|e| e.unwrap_or(BytecodeError::BadLabel { index }))?;
+        labels.push((name, ip));
+    }
+
+    let mut slots = Vec::new();
+    if version >= 2 {
+        let n_slots = r.u32()?;
+        for index in 0..n_slots as usize {
+            let addr = r.u32()? as usize;
+            let size = r.u32()? as usize;
+            let name = r
+                .string()
+                .map_err(|e| e.unwrap_or(BytecodeError::BadSlot { index }))?;
+            slots.push(Slot { name, addr, size });
+        }
+    }
+
+    let data = if version >= 3 {
+        let len = r.u32()? as usize;
+        r.take(len)?.to_vec()
+    } else {
+        Vec::new()
+    };
+
+    let mut hosts = Vec::new();
+    if version >= 4 {
+        let n_hosts = r.u32()?;
+        for index in 0..n_hosts as usize {
+            let name = r
+                .string()
+                .map_err(|e| e.unwrap_or(BytecodeError::BadHostName { index }))?;
+            hosts.push(name);
+        }
+    }
+
+    let n_code = r.u32()? as usize;
+    let mut code = Vec::new();
+    for ip in 0..n_code {
+        let instr = match r.u8()? {
+            0x00 => Instr::Print,
+            0x01 => Instr::Push,
+            0x02 => Instr::Pop,
+            0x03 => Instr::Top,
+            0x04 => Instr::Add,
+            0x05 => Instr::Sub,
+            0x06 => Instr::Div,
+            0x07 => Instr::Mul,
+            0x08 => {
+                let index = r.u32()?;
+                match constants.get(index as usize) {
+                    Some(&value) => Instr::Load(value),
+                    None => return Err(BytecodeError::BadConstant { ip, index }),
+                }
+            }
+            0x09 => Instr::Jmp(r.u32()? as usize),
+            0x0a => Instr::Jz(r.u32()? as usize),
+            0x0b => Instr::Jnz(r.u32()? as usize),
+            0x0c => Instr::Store(r.u32()? as usize),
+            0x0d => Instr::Fetch(r.u32()? as usize),
+            0x0e => Instr::Dup,
+            0x0f => Instr::Swap,
+            0x10 => Instr::Over,
+            0x11 => Instr::Call(r.u32()? as usize),
+            0x12 => Instr::Ret,
+            0x13 => Instr::Read,
+            0x14 => Instr::PrintC,
+            0x15 => Instr::PrintS(r.u32()? as usize),
+            0x16 => {
+                let index = r.u32()?;
+                match constants.get(index as usize) {
+                    Some(&value) => Instr::Set(value),
+                    None => return Err(BytecodeError::BadConstant { ip, index }),
+                }
+            }
+            0x17 => {
+                let index = r.u32()?;
+                if index as usize >= hosts.len() {
+                    return Err(BytecodeError::BadHost { ip, index });
+                }
+                Instr::Host(index as usize)
+            }
+            0x18 => Instr::IntToFloat,
+            0x19 => Instr::FloatToInt,
+            0x1a => Instr::Mod,
+            0x1b => Instr::Neg,
+            0x1c => Instr::Eq,
+            0x1d => Instr::Lt,
+            0x1e => Instr::Gt,
+            0x1f => Instr::And,
+            0x20 => Instr::Or,
+            0x21 => Instr::Xor,
+            0x22 => Instr::Not,
+            0x23 => Instr::Shl,
+            0x24 => Instr::Shr,
+            opcode => return Err(BytecodeError::BadOpcode { ip, opcode }),
+        };
+        code.push(instr);
+    }
+    if !r.bytes.is_empty() {
+        return Err(BytecodeError::TrailingBytes(r.bytes.len()));
+    }
+
+    Ok(Module {
+        code,
+        labels,
+        slots,
+        data,
+        hosts,
+    })
+}
+
+/// The string starting at `offset`, if it is the start of a valid string of the data segment.
+fn string_at(data: &[u8], offset: usize) -> Option<&str> {
+    if offset > 0 && data.get(offset - 1) != Some(&0) {
+        return None;
+    }
+    let text = data.get(offset..)?;
+    let len = text.iter().position(|&b| b == 0)?;
+    std::str::from_utf8(&text[..len]).ok()
+}
+
+/// Text form of `code` which assembles to the same instructions, jumps to a labeled instruction
+/// use the (first) label name, memory accesses within a slot the slot name, `PRINTS` its
+/// string literal and `HOST` the function name.
+pub fn disassemble(
+    code: &[Instr],
+    labels: &[(String, usize)],
+    slots: &[Slot],
+    data: &[u8],
+    hosts: &[String],
+) -> String {
+    let mut names: HashMap<usize, &str> = HashMap::new();
+    for (name, ip) in labels {
+        names.entry(*ip).or_insert(name);
+    }
+    let slot_name = |addr: usize| {
+        let slot = slots
+            .iter()
+            .find(|slot| addr >= slot.addr && addr < slot.addr + slot.size)?;
+        Some(match addr - slot.addr {
+            0 => slot.name.clone(),
+            offset => format!("{}+{}", slot.name, offset),
+        })
+    };
+
+    let mut out = String::new();
+    for slot in slots {
+        match slot.size {
+            1 => out.push_str(&format!("SLOT {}\n", slot.name)),
+            size => out.push_str(&format!("SLOT {} {}\n", slot.name, size)),
+        }
+    }
+    for ip in 0..=code.len() {
+        for (name, _) in labels.iter().filter(|(_, label_ip)| *label_ip == ip) {
+            out.push_str(&format!("{}:\n", name));
+        }
+        let instr = match code.get(ip) {
+            Some(instr) => instr,
+            None => break,
+        };
+        match instr {
+            Instr::Jmp(target) | Instr::Jz(target) | Instr::Jnz(target) | Instr::Call(target) => {
+                match names.get(target) {
+                    Some(name) => out.push_str(&format!("{} {}\n", instr.mnemonic(), name)),
+                    None => out.push_str(&format!("{}\n", instr)),
+                }
+            }
+            Instr::PrintS(offset) => match string_at(data, *offset) {
+                Some(text) => out.push_str(&format!("PRINTS {}\n", quote_string(text))),
+                None => out.push_str(&format!("{}\n", instr)),
+            },
+            Instr::Host(index) => match hosts.get(*index) {
+                Some(name) => out.push_str(&format!("HOST {}\n", name)),
+                None => out.push_str(&format!("{}\n", instr)),
+            },
+            Instr::Store(addr) | Instr::Fetch(addr) => match slot_name(*addr) {
+                Some(name) => out.push_str(&format!("{} {}\n", instr.mnemonic(), name)),
+                None => out.push_str(&format!("{}\n", instr)),
+            },
+            _ => out.push_str(&format!("{}\n", instr)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler, instr};
+
+    #[test]
+    fn round_trip() {
+        let source: Vec<String> =
+            "SLOT X\nSLOT BUF 3\nLOAD 10\nLOAD -3\nLOAD 10\nSTART: POP\nSET -3\nLOAD 0x7fffffffffffffff\nSET 2.5\nLOAD 2.5\nITOF\nFTOI\nMOD\nSHR\nNOT\nLOOP:\nPUSH\nSTORE X\n\
+             FETCH BUF+2\nSTORE 7\nHOST CLOCK\nJZ END\nJNZ 1\nJMP LOOP\nHOST LOG\n\
+             HOST CLOCK\nEND:"
+                .lines()
+                .map(String::from)
+                .collect();
+        let program = assembler::assemble(&source).unwrap();
+        let code = instr::parse(&program.instructions).unwrap();
+
+        let bytes = encode(
+            &code,
+            &program.labels,
+            &program.slots,
+            &program.data,
+            &program.hosts,
+        );
+        let module = decode(&bytes).unwrap();
+        assert_eq!(module.code, code);
+        assert_eq!(module.labels, program.labels);
+        assert_eq!(module.slots, program.slots);
+        assert_eq!(module.data, program.data);
+        assert_eq!(module.hosts, ["CLOCK", "LOG"]);
+
+        let text = disassemble(
+            &module.code,
+            &module.labels,
+            &module.slots,
+            &module.data,
+            &module.hosts,
+        );
+        assert!(text.contains("FETCH BUF+2\n"), "{}", text);
+        assert!(text.contains("HOST LOG\n"), "{}", text);
+        let lines: Vec<String> = text.lines().map(String::from).collect();
+        let again = assembler::assemble(&lines).unwrap();
+        assert_eq!(instr::parse(&again.instructions).unwrap(), code);
+        assert_eq!(again.labels, program.labels);
+        assert_eq!(again.slots, program.slots);
+        assert_eq!(again.data, program.data);
+        assert_eq!(again.hosts, program.hosts);
+
+        assert_eq!(
+            decode(&bytes[..bytes.len() - 1]).err(),
+            Some(BytecodeError::Truncated)
+        );
+        assert_eq!(decode(b"SVMA").err(), Some(BytecodeError::BadMagic));
+    }
+}
@@ -0,0 +1,466 @@
+//! Compiler for a tiny statement language to VM assembly:
+//!
+//! ```text
+//! # comments run until the end of the line
+//! n = 10;
+//! while n {
+//!     if n - 5 { print n; } else { print 0 - 5; }
+//!     n = n - 1;
+//! }
+//! ```
+//!
+//! Expressions support `+ - * /`, unary minus and parentheses on 64 bit integers (the VM's
+//! `Value::Int`, the language has no floats), conditions are true when nonzero. Expressions are
+//! evaluated into the register, intermediate values are kept on the stack and every variable gets
+//! its own memory slot. Variable names may not differ only in case, the assembler ignores case.
+
+use std::fmt;
+
+use crate::vm::MEMORY_SIZE;
+
+#[derive(Debug, PartialEq)]
+pub struct CompileError {
+    pub line: usize,
+    pub col: usize,
+    pub message: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.col, self.message)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Symbol(char),
+    Eof,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "'{}'", n),
+            Token::Ident(name) => write!(f, "'{}'", name),
+            Token::Symbol(c) => write!(f, "'{}'", c),
+            Token::Eof => write!(f, "end of file"),
+        }
+    }
+}
+
+const SYMBOLS: &str = "+-*/(){};=";
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize, usize)>, CompileError> {
+    let mut tokens = Vec::new();
+
+    for (idx, line) in source.lines().enumerate() {
+        let line_no = idx + 1;
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let col = i + 1;
+            if c == '#' {
+                break;
+            } else if c.is_whitespace() {
+                i += 1;
+            } else if c.is_ascii_digit() {
+                let start = i;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let digits: String = chars[start..i].iter().collect();
+                let n = digits.parse().map_err(|_| CompileError {
+                    line: line_no,
+                    col,
+                    message: format!("number {} does not fit into 64 bits", digits),
+                })?;
+                tokens.push((Token::Number(n), line_no, col));
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push((Token::Ident(chars[start..i].iter().collect()), line_no, col));
+            } else if SYMBOLS.contains(c) {
+                tokens.push((Token::Symbol(c), line_no, col));
+                i += 1;
+            } else {
+                return Err(CompileError {
+                    line: line_no,
+                    col,
+                    message: format!("unexpected character '{}'", c),
+                });
+            }
+        }
+    }
+
+    let line = source.matches('\n').count() + 1;
+    let col = source.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+    tokens.push((Token::Eof, line, col));
+    Ok(tokens)
+}
+
+const KEYWORDS: [&str; 4] = ["while", "if", "else", "print"];
+
+struct Compiler {
+    tokens: Vec<(Token, usize, usize)>,
+    pos: usize,
+    /// names and the line of the first assignment in the order of their slots
+    variables: Vec<(String, usize)>,
+    labels: usize,
+    out: Vec<String>,
+    lines: Vec<usize>,
+}
+
+impl Compiler {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].0.clone();
+        if token != Token::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, CompileError> {
+        let (_, line, col) = self.tokens[self.pos];
+        Err(CompileError { line, col, message })
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), CompileError> {
+        if *self.peek() == Token::Symbol(symbol) {
+            self.next();
+            Ok(())
+        } else {
+            self.error(format!("expected '{}', found {}", symbol, self.peek()))
+        }
+    }
+
+    /// Appends an instruction, attributed to the line of the last consumed token.
+    fn emit(&mut self, instruction: impl Into<String>) {
+        self.out.push(instruction.into());
+        self.lines.push(self.tokens[self.pos.saturating_sub(1)].1);
+    }
+
+    fn is_variable(&self, name: &str) -> bool {
+        self.variables.iter().any(|(var, _)| var == name)
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!("_L{}", self.labels)
+    }
+
+    fn program(&mut self) -> Result<(), CompileError> {
+        while *self.peek() != Token::Eof {
+            self.statement()?;
+        }
+        Ok(())
+    }
+
+    fn block(&mut self) -> Result<(), CompileError> {
+        self.expect('{')?;
+        while *self.peek() != Token::Symbol('}') {
+            if *self.peek() == Token::Eof {
+                return self.error("expected '}', found end of file".to_string());
+            }
+            self.statement()?;
+        }
+        self.expect('}')
+    }
+
+    fn statement(&mut self) -> Result<(), CompileError> {
+        match self.peek().clone() {
+            Token::Ident(kw) if kw == "print" => {
+                self.next();
+                self.expression()?;
+                self.emit("PRINT");
+                self.expect(';')
+            }
+            Token::Ident(kw) if kw == "while" => {
+                self.next();
+                let (start, end) = (self.label(), self.label());
+                self.emit(format!("{}:", start));
+                self.expression()?;
+                self.emit(format!("JZ {}", end));
+                self.block()?;
+                self.emit(format!("JMP {}", start));
+                self.emit(format!("{}:", end));
+                Ok(())
+            }
+            Token::Ident(kw) if kw == "if" => {
+                self.next();
+                let (otherwise, end) = (self.label(), self.label());
+                self.expression()?;
+                self.emit(format!("JZ {}", otherwise));
+                self.block()?;
+                if *self.peek() == Token::Ident("else".to_string()) {
+                    self.next();
+                    self.emit(format!("JMP {}", end));
+                    self.emit(format!("{}:", otherwise));
+                    match self.peek() {
+                        Token::Ident(kw) if kw == "if" => self.statement()?,
+                        _ => self.block()?,
+                    }
+                    self.emit(format!("{}:", end));
+                } else {
+                    self.emit(format!("{}:", otherwise));
+                }
+                Ok(())
+            }
+            Token::Ident(name) if !KEYWORDS.contains(&name.as_str()) => {
+                if let Some((var, _)) = self
+                    .variables
+                    .iter()
+                    .find(|(var, _)| *var != name && var.eq_ignore_ascii_case(&name))
+                {
+                    return self.error(format!(
+                        "variable '{}' only differs in case from '{}'",
+                        name, var
+                    ));
+                }
+                self.next();
+                self.expect('=')?;
+                self.expression()?;
+                if !self.is_variable(&name) {
+                    if self.variables.len() == MEMORY_SIZE {
+                        return self.error(format!("more than {} variables", MEMORY_SIZE));
+                    }
+                    let line = self.tokens[self.pos - 1].1;
+                    self.variables.push((name.clone(), line));
+                }
+                self.emit(format!("STORE {}", name));
+                self.expect(';')
+            }
+            token => self.error(format!("expected a statement, found {}", token)),
+        }
+    }
+
+    /// Evaluates `lhs op rhs` with `lhs` in the register, the instructions pop the left operand.
+    fn binary(
+        &mut self,
+        operators: &[(char, &'static str)],
+        operand: fn(&mut Self) -> Result<(), CompileError>,
+    ) -> Result<(), CompileError> {
+        operand(self)?;
+        loop {
+            let op = match self.peek() {
+                Token::Symbol(c) => operators.iter().find(|(sym, _)| sym == c),
+                _ => None,
+            };
+            let instruction = match op {
+                Some(&(_, instruction)) => instruction,
+                None => return Ok(()),
+            };
+            self.next();
+            self.emit("PUSH");
+            operand(self)?;
+            self.emit(instruction);
+        }
+    }
+
+    fn expression(&mut self) -> Result<(), CompileError> {
+        self.binary(&[('+', "ADD"), ('-', "SUB")], Self::term)
+    }
+
+    fn term(&mut self) -> Result<(), CompileError> {
+        self.binary(&[('*', "MUL"), ('/', "DIV")], Self::unary)
+    }
+
+    fn unary(&mut self) -> Result<(), CompileError> {
+        if *self.peek() == Token::Symbol('-') {
+            self.next();
+            self.unary()?;
+            // reg = 0 - reg
+            self.emit("LOAD 0");
+            self.emit("SUB");
+            return Ok(());
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<(), CompileError> {
+        match self.peek().clone() {
+            Token::Number(n) => {
+                self.next();
+                self.emit(format!("LOAD {}", n));
+                self.emit("POP");
+                Ok(())
+            }
+            Token::Ident(name) if !KEYWORDS.contains(&name.as_str()) => {
+                if self.is_variable(&name) {
+                    self.next();
+                    self.emit(format!("FETCH {}", name));
+                    Ok(())
+                } else {
+                    self.error(format!("undefined variable '{}'", name))
+                }
+            }
+            Token::Symbol('(') => {
+                self.next();
+                self.expression()?;
+                self.expect(')')
+            }
+            token => self.error(format!("expected an expression, found {}", token)),
+        }
+    }
+}
+
+/// Compiled program, `lines[i]` is the source line `assembly[i]` was generated from.
+pub struct Compiled {
+    pub assembly: Vec<String>,
+    pub lines: Vec<usize>,
+}
+
+/// Compiles `source` to assembly which can be passed to the assembler.
+pub fn compile(source: &str) -> Result<Compiled, CompileError> {
+    let mut compiler = Compiler {
+        tokens: tokenize(source)?,
+        pos: 0,
+        variables: Vec::new(),
+        labels: 0,
+        out: Vec::new(),
+        lines: Vec::new(),
+    };
+    compiler.program()?;
+
+    // every variable gets a slot, allocated in the order of their first assignment
+    let (mut assembly, mut lines): (Vec<String>, Vec<usize>) = compiler
+        .variables
+        .into_iter()
+        .map(|(name, line)| (format!("SLOT {}", name), line))
+        .unzip();
+    assembly.extend(compiler.out);
+    lines.extend(compiler.lines);
+    Ok(Compiled { assembly, lines })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::VmError;
+    use crate::value::Value;
+    use crate::vm::VMState;
+    use crate::{assembler, instr};
+
+    /// Compiles and runs `source`, returns the memory, variables are allocated in the order of
+    /// their first assignment.
+    fn run(source: &str) -> Result<Vec<i64>, VmError> {
+        let compiled = compile(source).unwrap();
+        assert_eq!(compiled.lines.len(), compiled.assembly.len());
+        let program = assembler::assemble(&compiled.assembly).unwrap();
+        let code = instr::parse(&program.instructions).unwrap();
+        let mut vm_state = VMState::default();
+        vm_state.run(&code)?;
+        assert!(vm_state.stack.is_empty(), "stack is not balanced");
+        Ok(vm_state
+            .memory
+            .iter()
+            .map(|value| match value {
+                Value::Int(n) => *n,
+                other => panic!("{} is not an integer", other),
+            })
+            .collect())
+    }
+
+    /// Like `run`, but reads the assembly back as the `compile` command writes it.
+    fn run_written(source: &str) -> Result<Vec<i64>, VmError> {
+        let text: String = compile(source)
+            .unwrap()
+            .assembly
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let program = assembler::assemble(&assembler::read_program(&text)).unwrap();
+        let mut vm_state = VMState::default();
+        vm_state.run(&instr::parse(&program.instructions).unwrap())?;
+        Ok(vm_state
+            .memory
+            .iter()
+            .map(|value| match value {
+                Value::Int(n) => *n,
+                other => panic!("{} is not an integer", other),
+            })
+            .collect())
+    }
+
+    fn error(source: &str) -> (usize, usize) {
+        let e = compile(source).err().expect("compiled");
+        (e.line, e.col)
+    }
+
+    #[test]
+    fn arithmetic() {
+        let memory = run(
+            "a = 2 + 3 * 4;\nb = (2 + 3) * 4;\nc = 20 - 8 - 2;\nd = 100 / 10 / 5;\ne = -a + --3;",
+        )
+        .unwrap();
+        assert_eq!(memory[..5], [14, 20, 10, 2, -11]);
+    }
+
+    #[test]
+    fn while_loop() {
+        let memory =
+            run("# sum of 1..10\nn = 10;\nsum = 0;\nwhile n {\n  sum = sum + n;\n  n = n - 1;\n}")
+                .unwrap();
+        assert_eq!(memory[..2], [0, 55]);
+    }
+
+    #[test]
+    fn nested_if_else() {
+        let source = "
+            i = 0; small = 0; five = 0; big = 0;
+            while 10 - i {
+                if i - 5 {
+                    if (i - 5) / 5 { big = big + 1; } else { small = small + 1; }
+                } else {
+                    five = five + 1;
+                }
+                i = i + 1;
+            }";
+        // (i - 5) / 5 is zero for 1..=9 and -1 for i = 0
+        assert_eq!(run(source).unwrap()[..4], [10, 8, 1, 1]);
+    }
+
+    #[test]
+    fn else_if() {
+        let source = "x = 2; r = 0;\nif x - 2 { r = 1; } else if x { r = 2; } else { r = 3; }";
+        assert_eq!(run(source).unwrap()[1], 2);
+    }
+
+    #[test]
+    fn runtime_error() {
+        assert_eq!(
+            run("a = 0; b = 1 / a;").err(),
+            Some(VmError::DivideByZero { ip: 7 })
+        );
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert_eq!(error("a = 1;\nb = a +;"), (2, 8));
+        assert_eq!(error("print x;"), (1, 7));
+        assert_eq!(error("a = 1\nprint a;"), (2, 1));
+        assert_eq!(error("while 1 {\n  print 1;\n"), (3, 1));
+        assert_eq!(error("a = 1 $ 2;"), (1, 7));
+        assert_eq!(error("if = 3;"), (1, 4));
+        assert_eq!(error("x = 99999999999999999999;"), (1, 5));
+        assert_eq!(error("n = 1;\nN = 2;"), (2, 1));
+    }
+
+    #[test]
+    fn written_assembly() {
+        let source = "Total = 0; count = 3; WHILE = 1;\n\
+                      while count { Total = Total + count; count = count - 1; }";
+        assert_eq!(run_written(source).unwrap(), run(source).unwrap());
+        assert_eq!(run_written(source).unwrap()[..3], [6, 0, 1]);
+    }
+}
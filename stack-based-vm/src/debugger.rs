@@ -0,0 +1,230 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+use crate::Loaded;
+use simple_vm::value::{self, Value};
+use simple_vm::vm::{Limits, VMState};
+
+const HELP: &str = "Commands:
+  break <line|label>  stop before the instruction at the source line or label
+  break               list the breakpoints
+  delete <line|label> remove a breakpoint
+  step [n]            execute one (or n) instructions
+  continue            run until the next breakpoint or the end of the program
+  print reg           show the register
+  print <slot>        show the memory cells of a slot
+  stack               show the stack, top last
+  backtrace           show the return addresses of the active calls
+  set reg <value>     change the register
+  where               show the current instruction
+  restart             reset the VM to the start of the program
+  quit";
+
+enum State {
+    Running,
+    Finished,
+    Faulted,
+}
+
+struct Debugger<'a> {
+    file_name: &'a str,
+    program: &'a Loaded,
+    vm_state: VMState,
+    limits: Limits,
+    state: State,
+    breakpoints: BTreeSet<usize>,
+}
+
+impl Debugger<'_> {
+    /// Resolves a label or a source line to an instruction index. A line without instruction
+    /// refers to the next instruction, bytecode programs use instruction indices instead.
+    fn resolve(&self, target: &str) -> Result<usize, String> {
+        if let Some(&(_, ip)) = self
+            .program
+            .labels
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(target))
+        {
+            return Ok(ip);
+        }
+
+        let n: usize = target
+            .parse()
+            .map_err(|_| format!("no label or line '{}'", target))?;
+        let ip = match &self.program.source {
+            Some((_, lines)) => lines.iter().position(|&line| line >= n),
+            None => Some(n),
+        };
+        match ip {
+            Some(ip) if ip < self.program.code.len() => Ok(ip),
+            _ => Err(format!("there is no instruction at or after {}", n)),
+        }
+    }
+
+    fn location(&self, ip: usize) -> String {
+        let (location, context) = self.program.locate(self.file_name, ip);
+        format!("ip {} at {}\n    {}", ip, location, context)
+    }
+
+    fn where_(&self) {
+        match self.state {
+            State::Running => println!("{}", self.location(self.vm_state.ip)),
+            State::Finished => println!("the program has finished"),
+            State::Faulted => println!("the program has faulted, use `restart`"),
+        }
+    }
+
+    /// Executes instructions until `hook` stops it, then shows where it stopped.
+    fn resume(&mut self, paused: &str, mut hook: impl FnMut(&VMState, &BTreeSet<usize>) -> bool) {
+        if !matches!(self.state, State::Running) {
+            self.where_();
+            return;
+        }
+
+        let breakpoints = &self.breakpoints;
+        match self
+            .vm_state
+            .run_with(&self.program.code, |state| hook(state, breakpoints))
+        {
+            Ok(false) => {
+                print!("{}", paused);
+                self.where_();
+            }
+            Ok(true) => {
+                self.state = State::Finished;
+                self.where_();
+            }
+            Err(error) => {
+                self.state = State::Faulted;
+                println!("error: {}", error);
+                println!("{}", self.location(error.ip()));
+            }
+        }
+    }
+
+    fn step(&mut self, n: usize) {
+        let mut executed = 0;
+        self.resume("", |_, _| {
+            executed += 1;
+            executed <= n
+        });
+    }
+
+    fn cont(&mut self) {
+        // the breakpoint we are stopped at must not stop us again
+        let mut first = true;
+        self.resume("breakpoint, ", |state, breakpoints| {
+            let stop = !first && breakpoints.contains(&state.ip);
+            first = false;
+            !stop
+        });
+    }
+
+    /// Executes a single command, returns `false` to quit.
+    fn command(&mut self, line: &str) -> bool {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            [] => {}
+            ["break" | "b"] => {
+                for &ip in &self.breakpoints {
+                    println!("{}", self.location(ip));
+                }
+            }
+            ["break" | "b", target] => match self.resolve(target) {
+                Ok(ip) => {
+                    self.breakpoints.insert(ip);
+                    println!("breakpoint at {}", self.location(ip));
+                }
+                Err(e) => println!("{}", e),
+            },
+            ["delete" | "d", target] => match self.resolve(target) {
+                Ok(ip) if self.breakpoints.remove(&ip) => {}
+                Ok(_) => println!("no breakpoint at {}", target),
+                Err(e) => println!("{}", e),
+            },
+            ["step" | "s"] => self.step(1),
+            ["step" | "s", n] => match n.parse() {
+                Ok(n) => self.step(n),
+                Err(_) => println!("invalid count '{}'", n),
+            },
+            ["continue" | "c"] => self.cont(),
+            ["print" | "p", "reg"] => println!("reg = {}", self.vm_state.reg),
+            ["print" | "p", name] => match self
+                .program
+                .slots
+                .iter()
+                .find(|slot| slot.name.eq_ignore_ascii_case(name))
+            {
+                Some(slot) if slot.addr + slot.size > self.vm_state.memory.len() => {
+                    println!("slot '{}' is beyond the memory limit", slot.name)
+                }
+                Some(slot) if slot.size == 1 => {
+                    println!("{} = {}", slot.name, self.vm_state.memory[slot.addr])
+                }
+                Some(slot) => println!(
+                    "{} = {}",
+                    slot.name,
+                    value::list(&self.vm_state.memory[slot.addr..slot.addr + slot.size])
+                ),
+                None => println!("no slot '{}'", name),
+            },
+            ["stack"] => println!("stack = {}", value::list(&self.vm_state.stack)),
+            ["backtrace" | "bt"] => {
+                for &ret in self.vm_state.call_stack.iter().rev() {
+                    // the call precedes its return address
+                    println!("called from {}", self.location(ret - 1));
+                }
+            }
+            ["set", "reg", literal] => match Value::parse(literal) {
+                Some(value) => self.vm_state.reg = value,
+                None => println!("invalid value '{}'", literal),
+            },
+            ["where" | "w"] => self.where_(),
+            ["restart"] => {
+                self.vm_state = self.program.vm_state(self.limits);
+                self.state = if self.program.code.is_empty() {
+                    State::Finished
+                } else {
+                    State::Running
+                };
+                self.where_();
+            }
+            ["help" | "h"] => println!("{}", HELP),
+            ["quit" | "q"] => return false,
+            _ => println!("unknown command '{}', try `help`", line.trim()),
+        }
+        true
+    }
+}
+
+/// Interactive debugger reading commands from stdin.
+pub fn debug(file_name: &str, program: &Loaded, limits: Limits) {
+    let mut debugger = Debugger {
+        file_name,
+        program,
+        vm_state: program.vm_state(limits),
+        limits,
+        state: State::Running,
+        breakpoints: BTreeSet::new(),
+    };
+    if program.code.is_empty() {
+        debugger.state = State::Finished;
+    }
+    debugger.where_();
+
+    let stdin = io::stdin();
+    let mut line = String::new();
+    loop {
+        print!("(svm) ");
+        let _ = io::stdout().flush();
+        line.clear();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                if !debugger.command(&line) {
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::fmt;
+
+use crate::assembler::{self, AsmError};
+use crate::bytecode::{self, BytecodeError};
+use crate::error::VmError;
+use crate::instr::{self, Instr};
+use crate::io::{Io, StdIo};
+use crate::value::Value;
+use crate::vm::{Limits, VMState};
+
+/// Why a program could not be loaded into a `Vm`.
+#[derive(Debug)]
+pub enum LoadError {
+    Asm(Vec<AsmError>),
+    /// An instruction of the assembled program is malformed.
+    Instr(VmError),
+    Bytecode(BytecodeError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Asm(errors) => {
+                for (idx, error) in errors.iter().enumerate() {
+                    if idx > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", error)?;
+                }
+                Ok(())
+            }
+            LoadError::Instr(error) => write!(f, "{}", error),
+            LoadError::Bytecode(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// A program together with the VM running it.
+pub struct Vm<I = StdIo> {
+    code: Vec<Instr>,
+    state: VMState<I>,
+}
+
+impl Vm {
+    /// Loads `program`, assembly text or bytecode, into a VM with the default limits which
+    /// reads from stdin and writes to stdout.
+    pub fn new(program: impl AsRef<[u8]>) -> Result<Self, LoadError> {
+        Self::with_io(program, Limits::default(), StdIo::default())
+    }
+}
+
+impl<I: Io> Vm<I> {
+    pub fn with_io(program: impl AsRef<[u8]>, limits: Limits, io: I) -> Result<Self, LoadError> {
+        let program = program.as_ref();
+        let (code, data, hosts) = if bytecode::is_bytecode(program) {
+            let module = bytecode::decode(program).map_err(LoadError::Bytecode)?;
+            (module.code, module.data, module.hosts)
+        } else {
+            let text = String::from_utf8_lossy(program);
+            let assembled =
+                assembler::assemble(&assembler::read_program(&text)).map_err(LoadError::Asm)?;
+            let code = instr::parse(&assembled.instructions).map_err(LoadError::Instr)?;
+            (code, assembled.data, assembled.hosts)
+        };
+
+        let mut state = VMState::with_io(limits, io);
+        state.data = data;
+        state.hosts = hosts;
+        Ok(Self { code, state })
+    }
+
+    /// Makes `f` the host function `name` (case insensitive), returns whether the program
+    /// uses it. The function is kept either way. Calling a host function of the program which
+    /// was not registered fails.
+    pub fn register(
+        &mut self,
+        name: &str,
+        f: impl FnMut(&mut VMState<I>) -> Result<(), String> + 'static,
+    ) -> bool {
+        self.state.bind_host(name, Box::new(f))
+    }
+
+    /// Executes the next instruction, returns whether there is more to execute.
+    pub fn step(&mut self) -> Result<bool, VmError> {
+        let mut first = true;
+        let finished = self
+            .state
+            .run_with(&self.code, |_| std::mem::replace(&mut first, false))?;
+        Ok(!finished)
+    }
+
+    /// Executes the program until it ends.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        self.state.run(&self.code)
+    }
+
+    pub fn finished(&self) -> bool {
+        self.state.ip >= self.code.len()
+    }
+
+    pub fn code(&self) -> &[Instr] {
+        &self.code
+    }
+
+    pub fn ip(&self) -> usize {
+        self.state.ip
+    }
+
+    pub fn reg(&self) -> Value {
+        self.state.reg
+    }
+
+    /// The stack, top last.
+    pub fn stack(&self) -> &[Value] {
+        &self.state.stack
+    }
+
+    pub fn memory(&self) -> &[Value] {
+        &self.state.memory
+    }
+
+    pub fn state(&self) -> &VMState<I> {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut VMState<I> {
+        &mut self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::io::MemoryIo;
+    use crate::vm::HostFn;
+
+    fn load(source: &str) -> Vm<MemoryIo> {
+        Vm::with_io(source, Limits::default(), MemoryIo::new("")).unwrap()
+    }
+
+    #[test]
+    fn step_and_inspect() {
+        let mut vm = load("LOAD 1\nLOAD 2\nPOP\nADD");
+        assert!(vm.step().unwrap());
+        assert_eq!(vm.stack(), [Value::Int(1)]);
+        assert!(vm.step().unwrap());
+        assert!(vm.step().unwrap());
+        assert_eq!((vm.ip(), vm.reg()), (3, Value::Int(2)));
+        assert!(!vm.step().unwrap());
+        assert!(vm.finished());
+        assert_eq!(vm.reg(), Value::Int(3));
+        assert!(vm.stack().is_empty());
+        assert!(!vm.step().unwrap());
+
+        let bytes = bytecode::encode(vm.code(), &[], &[], &[], &[]);
+        let mut vm = Vm::with_io(bytes, Limits::default(), MemoryIo::new("")).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.reg(), Value::Int(3));
+
+        assert!(matches!(
+            Vm::new("JMP NOWHERE").err(),
+            Some(LoadError::Asm(errors)) if errors.len() == 1
+        ));
+    }
+
+    #[test]
+    fn host_functions() {
+        // the host keeps its own state between calls
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = load("LOAD 2\nLOAD 3\nHOST sum\nHOST log\nSET 7\nHOST Log");
+        let sink = Rc::clone(&log);
+        assert!(vm.register("LOG", move |state| {
+            sink.borrow_mut().push(state.reg);
+            Ok(())
+        }));
+        assert!(vm.register("sum", |state| {
+            let mut sum = 0;
+            for value in state.stack.drain(..) {
+                match value {
+                    Value::Int(n) => sum += n,
+                    other => return Err(format!("cannot sum {}", other)),
+                }
+            }
+            state.reg = Value::Int(sum);
+            Ok(())
+        }));
+        assert!(!vm.register("unused", |_| Ok(())));
+        vm.run().unwrap();
+        assert_eq!(*log.borrow(), [Value::Int(5), Value::Int(7)]);
+
+        let mut vm = load("HOST missing");
+        assert_eq!(
+            vm.run(),
+            Err(VmError::UnboundHost {
+                ip: 0,
+                name: "MISSING".to_string()
+            })
+        );
+
+        let mut vm = load("SET 1\nHOST check");
+        vm.register("check", |state| match state.reg {
+            Value::Int(0) => Ok(()),
+            reg => Err(format!("expected 0, got {}", reg)),
+        });
+        assert_eq!(
+            vm.run(),
+            Err(VmError::Host {
+                ip: 1,
+                name: "CHECK".to_string(),
+                message: "expected 0, got 1".to_string()
+            })
+        );
+
+        // a host function cannot push past the stack limit
+        let limits = Limits {
+            max_stack: 2,
+            ..Limits::default()
+        };
+        let mut vm = Vm::with_io("HOST flood", limits, MemoryIo::new("")).unwrap();
+        vm.register("flood", |state| {
+            state.stack.extend([Value::Int(0); 3]);
+            Ok(())
+        });
+        assert_eq!(
+            vm.run(),
+            Err(VmError::StackOverflow {
+                ip: 0,
+                op: "HOST",
+                max: 2
+            })
+        );
+    }
+
+    #[test]
+    fn hosts_outlive_the_program() {
+        let mut state = VMState::with_io(Limits::default(), MemoryIo::new(""));
+        let double: HostFn<MemoryIo> = Box::new(|state| {
+            if let Value::Int(n) = state.reg {
+                state.reg = Value::Int(2 * n);
+            }
+            Ok(())
+        });
+        assert!(!state.bind_host("double", double));
+
+        // the function is found once a program uses it
+        state.hosts = vec!["DOUBLE".to_string()];
+        state.reg = Value::Int(4);
+        state.run(&[Instr::Host(0), Instr::Host(0)]).unwrap();
+        assert_eq!(state.reg, Value::Int(16));
+    }
+}
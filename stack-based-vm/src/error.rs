@@ -0,0 +1,245 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Runtime error of the VM, `ip` is the index of the failing instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    StackUnderflow {
+        ip: usize,
+        op: &'static str,
+    },
+    DivideByZero {
+        ip: usize,
+    },
+    Overflow {
+        ip: usize,
+        op: &'static str,
+    },
+    BadOperand {
+        ip: usize,
+        op: &'static str,
+        operand: Option<String>,
+    },
+    /// An operand beyond the `expected` number of operands of `op`.
+    UnexpectedOperand {
+        ip: usize,
+        op: &'static str,
+        operand: String,
+        expected: usize,
+    },
+    InvalidOpcode {
+        ip: usize,
+        opcode: String,
+    },
+    IpOutOfRange {
+        ip: usize,
+        target: usize,
+    },
+    BadAddress {
+        ip: usize,
+        op: &'static str,
+        addr: usize,
+    },
+    CallDepth {
+        ip: usize,
+        max: usize,
+    },
+    ReturnWithoutCall {
+        ip: usize,
+    },
+    /// `input` is `None` at the end of the input.
+    BadInput {
+        ip: usize,
+        input: Option<String>,
+    },
+    BadChar {
+        ip: usize,
+        value: i64,
+    },
+    /// The operands of a binary operation have different types.
+    TypeMismatch {
+        ip: usize,
+        op: &'static str,
+        left: &'static str,
+        right: &'static str,
+    },
+    /// `reg` holds a value of the type `found` instead of `expected`.
+    WrongType {
+        ip: usize,
+        op: &'static str,
+        expected: &'static str,
+        found: &'static str,
+    },
+    Io {
+        ip: usize,
+        op: &'static str,
+        message: String,
+    },
+    StackOverflow {
+        ip: usize,
+        op: &'static str,
+        max: usize,
+    },
+    /// `addr` is a valid address, but beyond the `max` cells the VM was given.
+    MemoryLimit {
+        ip: usize,
+        op: &'static str,
+        addr: usize,
+        max: usize,
+    },
+    StepLimit {
+        ip: usize,
+        max: u64,
+    },
+    Timeout {
+        ip: usize,
+        timeout: Duration,
+    },
+    /// No function was registered for the host function `name`.
+    UnboundHost {
+        ip: usize,
+        name: String,
+    },
+    /// The host function `name` failed with `message`.
+    Host {
+        ip: usize,
+        name: String,
+        message: String,
+    },
+}
+
+impl VmError {
+    pub fn ip(&self) -> usize {
+        match self {
+            VmError::StackUnderflow { ip, .. }
+            | VmError::DivideByZero { ip }
+            | VmError::Overflow { ip, .. }
+            | VmError::BadOperand { ip, .. }
+            | VmError::UnexpectedOperand { ip, .. }
+            | VmError::InvalidOpcode { ip, .. }
+            | VmError::IpOutOfRange { ip, .. }
+            | VmError::BadAddress { ip, .. }
+            | VmError::CallDepth { ip, .. }
+            | VmError::ReturnWithoutCall { ip }
+            | VmError::BadInput { ip, .. }
+            | VmError::BadChar { ip, .. }
+            | VmError::TypeMismatch { ip, .. }
+            | VmError::WrongType { ip, .. }
+            | VmError::Io { ip, .. }
+            | VmError::StackOverflow { ip, .. }
+            | VmError::MemoryLimit { ip, .. }
+            | VmError::StepLimit { ip, .. }
+            | VmError::Timeout { ip, .. }
+            | VmError::UnboundHost { ip, .. }
+            | VmError::Host { ip, .. } => *ip,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::StackUnderflow { ip, op } => {
+                write!(f, "[{}:{}] stack underflow", op, ip)
+            }
+            VmError::DivideByZero { ip } => write!(f, "[DIV:{}] division by zero", ip),
+            VmError::Overflow { ip, op } => write!(f, "[{}:{}] integer overflow", op, ip),
+            VmError::BadOperand {
+                ip,
+                op,
+                operand: Some(operand),
+            } => write!(f, "[{}:{}] could not parse operand '{}'", op, ip, operand),
+            VmError::BadOperand {
+                ip,
+                op,
+                operand: None,
+            } => write!(f, "[{}:{}] missing operand", op, ip),
+            VmError::UnexpectedOperand {
+                ip,
+                op,
+                operand,
+                expected,
+            } => write!(
+                f,
+                "[{}:{}] unexpected operand '{}', {} takes {}",
+                op,
+                ip,
+                operand,
+                op,
+                if *expected == 0 {
+                    "no operand"
+                } else {
+                    "one operand"
+                }
+            ),
+            VmError::InvalidOpcode { ip, opcode } => {
+                write!(f, "[{}] invalid instruction '{}'", ip, opcode)
+            }
+            VmError::IpOutOfRange { ip, target } => {
+                write!(f, "[{}] jump target {} is out of range", ip, target)
+            }
+            VmError::BadAddress { ip, op, addr } => {
+                write!(f, "[{}:{}] memory address {} is out of range", op, ip, addr)
+            }
+            VmError::CallDepth { ip, max } => {
+                write!(
+                    f,
+                    "[CALL:{}] call stack overflow, more than {} nested calls",
+                    ip, max
+                )
+            }
+            VmError::ReturnWithoutCall { ip } => {
+                write!(f, "[RET:{}] return without a matching call", ip)
+            }
+            VmError::BadInput {
+                ip,
+                input: Some(input),
+            } => write!(f, "[READ:{}] input '{}' is not an integer", ip, input),
+            VmError::BadInput { ip, input: None } => {
+                write!(f, "[READ:{}] unexpected end of input", ip)
+            }
+            VmError::BadChar { ip, value } => {
+                write!(f, "[PRINTC:{}] {} is not a valid character", ip, value)
+            }
+            VmError::TypeMismatch {
+                ip,
+                op,
+                left,
+                right,
+            } => write!(f, "[{}:{}] type mismatch, {} and {}", op, ip, left, right),
+            VmError::WrongType {
+                ip,
+                op,
+                expected,
+                found,
+            } => write!(f, "[{}:{}] expected {}, found {}", op, ip, expected, found),
+            VmError::Io { ip, op, message } => write!(f, "[{}:{}] {}", op, ip, message),
+            VmError::StackOverflow { ip, op, max } => {
+                write!(
+                    f,
+                    "[{}:{}] stack overflow, more than {} values",
+                    op, ip, max
+                )
+            }
+            VmError::MemoryLimit { ip, op, addr, max } => write!(
+                f,
+                "[{}:{}] memory address {} exceeds the limit of {} cells",
+                op, ip, addr, max
+            ),
+            VmError::StepLimit { ip, max } => {
+                write!(f, "[{}] step limit of {} instructions exceeded", ip, max)
+            }
+            VmError::Timeout { ip, timeout } => {
+                write!(f, "[{}] time limit of {:?} exceeded", ip, timeout)
+            }
+            VmError::UnboundHost { ip, name } => {
+                write!(f, "[HOST:{}] no host function '{}' registered", ip, name)
+            }
+            VmError::Host { ip, name, message } => {
+                write!(f, "[HOST:{}] {}: {}", ip, name, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
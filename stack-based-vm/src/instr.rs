@@ -0,0 +1,283 @@
+use std::fmt;
+
+use crate::error::VmError;
+use crate::value::Value;
+
+/// A decoded instruction, operands are parsed once before execution.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instr {
+    Print,
+    Push,
+    Pop,
+    Top,
+    Add,
+    Sub,
+    Div,
+    Mul,
+    Load(Value),
+    Jmp(usize),
+    Jz(usize),
+    Jnz(usize),
+    Store(usize),
+    Fetch(usize),
+    Dup,
+    Swap,
+    Over,
+    Call(usize),
+    Ret,
+    Read,
+    PrintC,
+    /// Offset of a NUL terminated string in the data segment.
+    PrintS(usize),
+    /// Loads a value into `reg`, like `LOAD` followed by `POP` without touching the stack.
+    Set(Value),
+    /// Calls the host function with the index in the program's table of host names.
+    Host(usize),
+    /// Converts the integer in `reg` to a float.
+    IntToFloat,
+    /// Converts the float in `reg` to an integer, rounding towards zero.
+    FloatToInt,
+    Mod,
+    Neg,
+    /// Comparisons leave 1 in `reg` if they hold and 0 otherwise.
+    Eq,
+    Lt,
+    Gt,
+    /// Bitwise operations on integers.
+    And,
+    Or,
+    Xor,
+    Not,
+    Shl,
+    Shr,
+}
+
+fn operand<T: std::str::FromStr>(
+    arg: Option<&&str>,
+    ip: usize,
+    op: &'static str,
+) -> Result<T, VmError> {
+    let arg = arg.ok_or(VmError::BadOperand {
+        ip,
+        op,
+        operand: None,
+    })?;
+    arg.parse().map_err(|_| VmError::BadOperand {
+        ip,
+        op,
+        operand: Some(arg.to_string()),
+    })
+}
+
+/// The literal operand of `LOAD` and `SET`.
+fn literal(arg: Option<&&str>, ip: usize, op: &'static str) -> Result<Value, VmError> {
+    let arg = arg.ok_or(VmError::BadOperand {
+        ip,
+        op,
+        operand: None,
+    })?;
+    Value::parse(arg).ok_or_else(|| VmError::BadOperand {
+        ip,
+        op,
+        operand: Some(arg.to_string()),
+    })
+}
+
+impl Instr {
+    /// Decodes the textual instruction at `ip`, e.g. `LOAD 10` or `JNZ 2`.
+    pub fn parse(line: &str, ip: usize) -> Result<Self, VmError> {
+        let parts: Vec<&str> = line.split_ascii_whitespace().collect();
+        let arg = parts.get(1);
+        let instr = match parts.first().copied().unwrap_or_default() {
+            "PRINT" => Instr::Print,
+            "PUSH" => Instr::Push,
+            "POP" => Instr::Pop,
+            "TOP" => Instr::Top,
+            "ADD" => Instr::Add,
+            "SUB" => Instr::Sub,
+            "DIV" => Instr::Div,
+            "MUL" => Instr::Mul,
+            "LOAD" => Instr::Load(literal(arg, ip, "LOAD")?),
+            "JMP" => Instr::Jmp(operand(arg, ip, "JMP")?),
+            "JZ" => Instr::Jz(operand(arg, ip, "JZ")?),
+            "JNZ" => Instr::Jnz(operand(arg, ip, "JNZ")?),
+            "STORE" => Instr::Store(operand(arg, ip, "STORE")?),
+            "FETCH" => Instr::Fetch(operand(arg, ip, "FETCH")?),
+            "DUP" => Instr::Dup,
+            "SWAP" => Instr::Swap,
+            "OVER" => Instr::Over,
+            "CALL" => Instr::Call(operand(arg, ip, "CALL")?),
+            "RET" => Instr::Ret,
+            "READ" => Instr::Read,
+            "PRINTC" => Instr::PrintC,
+            "PRINTS" => Instr::PrintS(operand(arg, ip, "PRINTS")?),
+            "SET" => Instr::Set(literal(arg, ip, "SET")?),
+            "ITOF" => Instr::IntToFloat,
+            "FTOI" => Instr::FloatToInt,
+            "MOD" => Instr::Mod,
+            "NEG" => Instr::Neg,
+            "EQ" => Instr::Eq,
+            "LT" => Instr::Lt,
+            "GT" => Instr::Gt,
+            "AND" => Instr::And,
+            "OR" => Instr::Or,
+            "XOR" => Instr::Xor,
+            "NOT" => Instr::Not,
+            "SHL" => Instr::Shl,
+            "SHR" => Instr::Shr,
+            "HOST" => Instr::Host(operand(arg, ip, "HOST")?),
+            opcode => {
+                return Err(VmError::InvalidOpcode {
+                    ip,
+                    opcode: opcode.to_string(),
+                })
+            }
+        };
+        let expected = instr.operand_count();
+        match parts.get(expected + 1) {
+            Some(operand) => Err(VmError::UnexpectedOperand {
+                ip,
+                op: instr.mnemonic(),
+                operand: operand.to_string(),
+                expected,
+            }),
+            None => Ok(instr),
+        }
+    }
+
+    /// Number of operands in the textual form, 0 or 1.
+    pub fn operand_count(&self) -> usize {
+        match self {
+            Instr::Load(_)
+            | Instr::Set(_)
+            | Instr::Jmp(_)
+            | Instr::Jz(_)
+            | Instr::Jnz(_)
+            | Instr::Store(_)
+            | Instr::Fetch(_)
+            | Instr::Call(_)
+            | Instr::PrintS(_)
+            | Instr::Host(_) => 1,
+            _ => 0,
+        }
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instr::Print => "PRINT",
+            Instr::Push => "PUSH",
+            Instr::Pop => "POP",
+            Instr::Top => "TOP",
+            Instr::Add => "ADD",
+            Instr::Sub => "SUB",
+            Instr::Div => "DIV",
+            Instr::Mul => "MUL",
+            Instr::Load(_) => "LOAD",
+            Instr::Jmp(_) => "JMP",
+            Instr::Jz(_) => "JZ",
+            Instr::Jnz(_) => "JNZ",
+            Instr::Store(_) => "STORE",
+            Instr::Fetch(_) => "FETCH",
+            Instr::Dup => "DUP",
+            Instr::Swap => "SWAP",
+            Instr::Over => "OVER",
+            Instr::Call(_) => "CALL",
+            Instr::Ret => "RET",
+            Instr::Read => "READ",
+            Instr::PrintC => "PRINTC",
+            Instr::PrintS(_) => "PRINTS",
+            Instr::Set(_) => "SET",
+            Instr::Host(_) => "HOST",
+            Instr::IntToFloat => "ITOF",
+            Instr::FloatToInt => "FTOI",
+            Instr::Mod => "MOD",
+            Instr::Neg => "NEG",
+            Instr::Eq => "EQ",
+            Instr::Lt => "LT",
+            Instr::Gt => "GT",
+            Instr::And => "AND",
+            Instr::Or => "OR",
+            Instr::Xor => "XOR",
+            Instr::Not => "NOT",
+            Instr::Shl => "SHL",
+            Instr::Shr => "SHR",
+        }
+    }
+
+    /// Target of a jump or call.
+    pub fn target(&self) -> Option<usize> {
+        match *self {
+            Instr::Jmp(target) | Instr::Jz(target) | Instr::Jnz(target) | Instr::Call(target) => {
+                Some(target)
+            }
+            _ => None,
+        }
+    }
+
+    /// Number of values the instruction needs on the stack and by how much it changes the
+    /// stack depth. `CALL`, `RET` and `HOST` depend on the procedure and count as neutral.
+    pub fn stack_effect(&self) -> (usize, isize) {
+        match self {
+            Instr::Push | Instr::Load(_) => (0, 1),
+            Instr::Pop
+            | Instr::Add
+            | Instr::Sub
+            | Instr::Div
+            | Instr::Mul
+            | Instr::Mod
+            | Instr::Eq
+            | Instr::Lt
+            | Instr::Gt
+            | Instr::And
+            | Instr::Or
+            | Instr::Xor
+            | Instr::Shl
+            | Instr::Shr => (1, -1),
+            Instr::Top => (1, 0),
+            Instr::Dup => (1, 1),
+            Instr::Swap => (2, 0),
+            Instr::Over => (2, 1),
+            Instr::Print
+            | Instr::PrintC
+            | Instr::PrintS(_)
+            | Instr::Read
+            | Instr::Set(_)
+            | Instr::Host(_)
+            | Instr::IntToFloat
+            | Instr::FloatToInt
+            | Instr::Neg
+            | Instr::Not
+            | Instr::Jmp(_)
+            | Instr::Jz(_)
+            | Instr::Jnz(_)
+            | Instr::Store(_)
+            | Instr::Fetch(_)
+            | Instr::Call(_)
+            | Instr::Ret => (0, 0),
+        }
+    }
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instr::Load(value) | Instr::Set(value) => write!(f, "{} {}", self.mnemonic(), value),
+            Instr::Jmp(target) | Instr::Jz(target) | Instr::Jnz(target) | Instr::Call(target) => {
+                write!(f, "{} {}", self.mnemonic(), target)
+            }
+            Instr::Store(addr) | Instr::Fetch(addr) | Instr::PrintS(addr) | Instr::Host(addr) => {
+                write!(f, "{} {}", self.mnemonic(), addr)
+            }
+            _ => f.write_str(self.mnemonic()),
+        }
+    }
+}
+
+/// Decodes all instructions, the first malformed instruction is reported.
+pub fn parse(program: &[String]) -> Result<Vec<Instr>, VmError> {
+    program
+        .iter()
+        .enumerate()
+        .map(|(ip, line)| Instr::parse(line, ip))
+        .collect()
+}
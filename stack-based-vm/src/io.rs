@@ -0,0 +1,66 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+
+/// Input and output of a VM, used by `READ` and the `PRINT` instructions.
+pub trait Io {
+    /// Next whitespace separated word of the input, `None` at the end of the input.
+    fn read_word(&mut self) -> io::Result<Option<String>>;
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()>;
+}
+
+/// Reads from stdin and writes to stdout.
+#[derive(Default)]
+pub struct StdIo {
+    words: VecDeque<String>,
+}
+
+impl Io for StdIo {
+    fn read_word(&mut self) -> io::Result<Option<String>> {
+        // show a prompt written without newline before blocking
+        io::stdout().flush()?;
+        while self.words.is_empty() {
+            let mut line = String::new();
+            if io::stdin().lock().read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            self.words.extend(line.split_whitespace().map(String::from));
+        }
+        Ok(self.words.pop_front())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        io::stdout().write_all(bytes)
+    }
+}
+
+/// Reads from a fixed input and collects the output, e.g. for tests.
+#[derive(Default)]
+pub struct MemoryIo {
+    input: VecDeque<String>,
+    pub output: Vec<u8>,
+}
+
+impl MemoryIo {
+    pub fn new(input: &str) -> Self {
+        Self {
+            input: input.split_whitespace().map(String::from).collect(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn output(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+}
+
+impl Io for MemoryIo {
+    fn read_word(&mut self) -> io::Result<Option<String>> {
+        Ok(self.input.pop_front())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.extend_from_slice(bytes);
+        Ok(())
+    }
+}
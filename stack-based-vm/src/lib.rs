@@ -0,0 +1,39 @@
+//! A small stack based virtual machine with a single register, its assembler, bytecode format
+//! and a compiler for a tiny high-level language.
+//!
+//! `Vm` embeds the VM into other programs, which can provide native functions to it:
+//!
+//! ```
+//! use simple_vm::io::MemoryIo;
+//! use simple_vm::{Limits, Value, Vm};
+//!
+//! let program = "LOAD 6\nPOP\nHOST square\nPRINT";
+//! let mut vm = Vm::with_io(program, Limits::default(), MemoryIo::new("")).unwrap();
+//! vm.register("square", |state| match state.reg {
+//!     Value::Int(n) => {
+//!         state.reg = Value::Int(n * n);
+//!         Ok(())
+//!     }
+//!     _ => Err("expected an integer".to_string()),
+//! });
+//! vm.run().unwrap();
+//! assert_eq!(vm.reg(), Value::Int(36));
+//! assert_eq!(vm.state().io.output(), "36\n");
+//! ```
+
+pub mod assembler;
+pub mod bytecode;
+pub mod compiler;
+mod engine;
+pub mod error;
+pub mod instr;
+pub mod io;
+pub mod optimize;
+pub mod value;
+pub mod verify;
+pub mod vm;
+
+pub use engine::{LoadError, Vm};
+pub use error::VmError;
+pub use value::Value;
+pub use vm::{HostFn, Limits, VMState};
@@ -1,189 +1,470 @@
+use std::cmp::Reverse;
 use std::collections::HashMap;
-use std::fs::read_to_string;
 use std::env;
+use std::fmt;
+use std::fs;
 use std::process;
+use std::time::Duration;
 
-struct VMState {
-    ip: usize,
-    reg: i32,
-    stack: Vec<i32>,
+mod debugger;
+
+use simple_vm::assembler::{self, Slot};
+use simple_vm::instr::{self, Instr};
+use simple_vm::vm::{Limits, VMState};
+use simple_vm::{bytecode, compiler, optimize, value, verify, VmError};
+
+const USAGE: &str = "Usage: simple-vm [run] <program>
+       simple-vm asm <program.dat> <program.svmb>
+       simple-vm disasm <program.svmb> [program.dat]
+       simple-vm debug <program>
+       simple-vm verify <program>
+       simple-vm compile <program.svl> [program.dat]
+
+Programs are assembly text, bytecode or, with the extension .svl, high-level source.
+
+Options of run and debug:
+  --max-call-depth <n>  maximum number of nested CALLs (default 1024)
+  --max-stack <n>       maximum number of values on the stack (default 1048576)
+  --memory <n>          number of memory cells (default and maximum 256)
+  --max-steps <n>       stop after executing n instructions
+  --timeout <seconds>   stop a run after the given wall-clock time
+
+Options of run:
+  --trace               print every executed instruction with ip, reg and top of the stack
+  --profile             print execution counts per line and per opcode at exit
+  --optimize            run the program after the peephole optimizer
+  --emit-optimized      print the optimized program instead of running it";
+
+/// A program ready to run, either assembled from text or decoded from bytecode.
+struct Loaded {
+    code: Vec<Instr>,
+    labels: Vec<(String, usize)>,
+    slots: Vec<Slot>,
+    data: Vec<u8>,
+    hosts: Vec<String>,
+    /// source lines and the line of every instruction, text programs only
+    source: Option<(Vec<String>, Vec<usize>)>,
 }
 
-impl VMState {
-    fn new() -> Self {
-        Self {
-            ip : 0,
-            reg : 0,
-            stack : Vec::new()
+impl Loaded {
+    /// A fresh VM with the data segment of the program.
+    fn vm_state(&self, limits: Limits) -> VMState {
+        let mut vm_state = VMState::with_limits(limits);
+        vm_state.data = self.data.clone();
+        vm_state.hosts = self.hosts.clone();
+        vm_state
+    }
+
+    /// The program after the peephole optimizer, labels and source lines moved along.
+    fn optimized(self) -> Loaded {
+        let optimized = optimize::optimize(&self.code);
+        let index = &optimized.index;
+        Loaded {
+            labels: self
+                .labels
+                .into_iter()
+                .map(|(name, ip)| (name, index[ip]))
+                .collect(),
+            slots: self.slots,
+            data: self.data,
+            hosts: self.hosts,
+            source: self.source.map(|(source, lines)| {
+                let lines = optimized.origin.iter().map(|&ip| lines[ip]).collect();
+                (source, lines)
+            }),
+            code: optimized.code,
+        }
+    }
+
+    /// Location of the instruction at `ip` and its source (or disassembly) for diagnostics.
+    fn locate(&self, file_name: &str, ip: usize) -> (String, String) {
+        match &self.source {
+            Some((source, lines)) => {
+                let line = lines[ip];
+                (
+                    format!("{}:{}", file_name, line),
+                    format!("{} | {}", line, source[line - 1].trim()),
+                )
+            }
+            None => (
+                format!("{}: ip {}", file_name, ip),
+                format!("{} | {}", ip, self.code[ip]),
+            ),
         }
     }
 }
 
-fn ex_print(_: Option<&&str>, vm_state: &mut VMState) {
-    println!("{}", vm_state.reg);
-    vm_state.ip += 1;
+/// Formats an error at `line` of the source, with a caret below `col` if the column is known.
+fn diagnostic(
+    file_name: &str,
+    line: usize,
+    col: Option<usize>,
+    message: impl fmt::Display,
+    source_line: &str,
+) -> String {
+    let gutter = " ".repeat(line.to_string().len());
+    match col {
+        Some(col) => format!(
+            "{}:{}:{}: error: {}\n    {} | {}\n    {} | {}^",
+            file_name,
+            line,
+            col,
+            message,
+            line,
+            source_line,
+            gutter,
+            " ".repeat(col - 1)
+        ),
+        None => format!(
+            "{}:{}: error: {}\n    {} | {}",
+            file_name,
+            line,
+            message,
+            line,
+            source_line.trim()
+        ),
+    }
 }
-fn ex_push(_: Option<&&str>, vm_state: &mut VMState) {
-    vm_state.stack.push(vm_state.reg);
-    vm_state.ip += 1;
+
+/// Compiles high-level source to assembly.
+fn compile(file_name: &str, content: &str) -> Result<compiler::Compiled, Vec<String>> {
+    compiler::compile(content).map_err(|e| {
+        let line = content.lines().nth(e.line - 1).unwrap_or_default();
+        vec![diagnostic(file_name, e.line, Some(e.col), &e.message, line)]
+    })
 }
-fn ex_pop(_: Option<&&str>, vm_state: &mut VMState) {
-    if let Some(value) = vm_state.stack.pop() {
-        vm_state.reg = value;
-        vm_state.ip += 1;
-    } else {
-        panic!(format!(
-            "[POP:{}] Tried to pop from empty stack!",
-            vm_state.ip
-        ));
+
+/// Loads a text or bytecode program, on failure the diagnostics are returned.
+fn load(file_name: &str) -> Result<Loaded, Vec<String>> {
+    let content = fs::read(file_name)
+        .map_err(|e| vec![format!("{}: could not open the file: {}", file_name, e)])?;
+
+    if bytecode::is_bytecode(&content) {
+        let module =
+            bytecode::decode(&content).map_err(|e| vec![format!("{}: {}", file_name, e)])?;
+        return Ok(Loaded {
+            code: module.code,
+            labels: module.labels,
+            slots: module.slots,
+            data: module.data,
+            hosts: module.hosts,
+            source: None,
+        });
     }
-}
-fn ex_top(_: Option<&&str>, vm_state: &mut VMState) {
-    if let Some(value) = vm_state.stack.get(0) {
-        vm_state.reg = *value;
-        vm_state.ip += 1;
+
+    let content = String::from_utf8(content).map_err(|_| {
+        vec![format!(
+            "{}: neither bytecode nor a text program",
+            file_name
+        )]
+    })?;
+    // diagnostics of compiled programs refer to the high-level source
+    let source: Vec<String> = content.lines().map(String::from).collect();
+    let (assembly, origin) = if file_name.ends_with(".svl") {
+        let compiled = compile(file_name, &content)?;
+        (compiled.assembly, Some(compiled.lines))
     } else {
-        panic!(format!(
-            "[TOP:{}] Tried to peek empty stack!",
-            vm_state.ip
-        ));
-    }
+        (assembler::read_program(&content), None)
+    };
+    // columns only make sense in hand-written assembly
+    let position = |line: usize, token: usize| match &origin {
+        Some(origin) => (origin[line - 1], None),
+        None => (
+            line,
+            Some(assembler::token_column(&assembly[line - 1], token)),
+        ),
+    };
+    let program = assembler::assemble(&assembly).map_err(|errors| {
+        errors
+            .iter()
+            .map(|error| {
+                let (line, col) = match &origin {
+                    Some(origin) => (origin[error.line - 1], None),
+                    None => (error.line, Some(error.col)),
+                };
+                diagnostic(file_name, line, col, &error.kind, &source[line - 1])
+            })
+            .collect::<Vec<_>>()
+    })?;
+    let code = instr::parse(&program.instructions).map_err(|error| {
+        let token = match error {
+            VmError::InvalidOpcode { .. } => 0,
+            VmError::UnexpectedOperand { expected, .. } => expected + 1,
+            _ => 1,
+        };
+        let (line, col) = position(program.lines[error.ip()], token);
+        vec![diagnostic(file_name, line, col, &error, &source[line - 1])]
+    })?;
+    let lines: Vec<usize> = match origin {
+        Some(origin) => program.lines.iter().map(|&line| origin[line - 1]).collect(),
+        None => program.lines,
+    };
+
+    Ok(Loaded {
+        code,
+        labels: program.labels,
+        slots: program.slots,
+        data: program.data,
+        hosts: program.hosts,
+        source: Some((source, lines)),
+    })
 }
-fn ex_add(_: Option<&&str>, vm_state: &mut VMState) {
-    if let Some(value) = vm_state.stack.pop() {
-        vm_state.reg += value;
-        vm_state.ip += 1;
-    } else {
-        panic!(format!(
-            "[ADD:{}] Tried to pop from empty stack!",
-            vm_state.ip
-        ));
-    }
+
+fn load_or_exit(file_name: &str) -> Loaded {
+    load(file_name).unwrap_or_else(|errors| {
+        for error in errors {
+            eprintln!("{}", error);
+        }
+        process::exit(1);
+    })
 }
-fn ex_sub(_: Option<&&str>, vm_state: &mut VMState) {
-    if let Some(value) = vm_state.stack.pop() {
-        vm_state.reg = value - vm_state.reg;
-        vm_state.ip += 1;
-    } else {
-        panic!(format!(
-            "[SUB:{}] Tried to pop from empty stack!",
-            vm_state.ip
-        ));
+
+fn write_or_exit(file_name: &str, content: impl AsRef<[u8]>) {
+    if let Err(e) = fs::write(file_name, content) {
+        eprintln!("{}: could not write the file: {}", file_name, e);
+        process::exit(1);
     }
 }
-fn ex_div(_: Option<&&str>, vm_state: &mut VMState) {
-    if let Some(value) = vm_state.stack.pop() {
-        vm_state.reg = value / vm_state.reg;
-        vm_state.ip += 1;
-    } else {
-        panic!(format!(
-            "[DIV:{}] Tried to pop from empty stack!",
-            vm_state.ip
-        ));
+
+/// Prints the execution counts, `counts` holds the count of every instruction.
+fn print_profile(file_name: &str, program: &Loaded, counts: &[u64], steps: u64) {
+    let percent = |count: u64| 100.0 * count as f64 / steps.max(1) as f64;
+
+    // instructions sharing a source line are counted together
+    let mut lines: Vec<(String, u64)> = Vec::new();
+    let mut line_index = HashMap::new();
+    let mut opcodes: Vec<(&str, u64)> = Vec::new();
+    for (ip, &count) in counts.iter().enumerate().filter(|(_, &count)| count > 0) {
+        let (_, context) = program.locate(file_name, ip);
+        let idx = *line_index.entry(context.clone()).or_insert_with(|| {
+            lines.push((context, 0));
+            lines.len() - 1
+        });
+        lines[idx].1 += count;
+        let mnemonic = program.code[ip].mnemonic();
+        match opcodes.iter_mut().find(|(name, _)| *name == mnemonic) {
+            Some((_, total)) => *total += count,
+            None => opcodes.push((mnemonic, count)),
+        }
     }
-}
-fn ex_mul(_: Option<&&str>, vm_state: &mut VMState) {
-    if let Some(value) = vm_state.stack.pop() {
-        vm_state.reg *= value;
-        vm_state.ip += 1;
-    } else {
-        panic!(format!(
-            "[MUL:{}] Tried to pop from empty stack!",
-            vm_state.ip
-        ));
+    lines.sort_by_key(|&(_, count)| Reverse(count));
+    opcodes.sort_by_key(|&(_, count)| Reverse(count));
+
+    eprintln!("profile: {} steps", steps);
+    eprintln!("{:>12} {:>7}  line", "count", "%");
+    for (context, count) in &lines {
+        eprintln!("{:>12} {:>6.2}%  {}", count, percent(*count), context);
+    }
+    eprintln!("{:>12} {:>7}  opcode", "count", "%");
+    for (mnemonic, count) in &opcodes {
+        eprintln!("{:>12} {:>6.2}%  {}", count, percent(*count), mnemonic);
     }
 }
-fn ex_load(arg: Option<&&str>, vm_state: &mut VMState) {
-    if let Some(arg) = arg {
-        vm_state.stack.push(
-            arg.parse()
-                .expect(&format!("[LOAD:{}] Could not parse '{}'", vm_state.ip, arg)),
+
+fn run(file_name: &str, options: &Options) {
+    let mut program = load_or_exit(file_name);
+    if options.optimize || options.emit_optimized {
+        program = program.optimized();
+    }
+    if options.emit_optimized {
+        print!(
+            "{}",
+            bytecode::disassemble(
+                &program.code,
+                &program.labels,
+                &program.slots,
+                &program.data,
+                &program.hosts
+            )
         );
-        vm_state.ip += 1;
-    } else {
-        panic!(format!("[LOAD:{}] Requires one int argument!", vm_state.ip));
+        return;
     }
-}
-fn ex_jmp(arg: Option<&&str>, vm_state: &mut VMState) {
-    if let Some(arg) = arg {
-        vm_state.ip = arg
-            .parse()
-            .expect(&format!("[JMP:{}] Could not parse '{}'", vm_state.ip, arg));
-    } else {
-        panic!(format!("[JMP:{}] Requires one uint argument!", vm_state.ip));
-    }
-}
-fn ex_jz(arg: Option<&&str>, vm_state: &mut VMState) {
-    if let Some(arg) = arg {
-        let n_ip = arg
-            .parse()
-            .expect(&format!("[JZ:{}] Could not parse '{}'", vm_state.ip, arg));
-        if vm_state.reg == 0 {
-            vm_state.ip = n_ip;
-        } else {
-            vm_state.ip += 1;
-        }
-    } else {
-        panic!(format!("[JZ:{}] Requires one int argument!", vm_state.ip));
-    }
-}
-fn ex_jnz(arg: Option<&&str>, vm_state: &mut VMState) {
-    if let Some(arg) = arg {
-        let n_ip = arg
-            .parse()
-            .expect(&format!("[JNZ:{}] Could not parse '{}'", vm_state.ip, arg));
-        if vm_state.reg != 0 {
-            vm_state.ip = n_ip;
-        } else {
-            vm_state.ip += 1;
-        }
+
+    let mut vm_state = program.vm_state(options.limits);
+    let mut counts = vec![0; program.code.len()];
+    // the hook costs time, plain runs go without
+    let result = if options.trace || options.profile {
+        vm_state
+            .run_with(&program.code, |state| {
+                if options.trace {
+                    let top = state
+                        .stack
+                        .last()
+                        .map_or("-".to_string(), |top| top.to_string());
+                    eprintln!(
+                        "[{:>5}] {:<12} reg = {}, top = {}",
+                        state.ip,
+                        program.code[state.ip].to_string(),
+                        state.reg,
+                        top
+                    );
+                }
+                if options.profile {
+                    counts[state.ip] += 1;
+                }
+                true
+            })
+            .map(|_| ())
     } else {
-        panic!(format!("[JNZ:{}] Requires one int argument!", vm_state.ip));
+        vm_state.run(&program.code)
+    };
+    if options.profile {
+        print_profile(file_name, &program, &counts, vm_state.steps);
+    }
+    if let Err(error) = result {
+        let (location, context) = program.locate(file_name, error.ip());
+        eprintln!("{}: error: {}", location, error);
+        eprintln!("    {}", context);
+        eprintln!(
+            "    reg = {}, stack = {}",
+            vm_state.reg,
+            value::list(&vm_state.stack)
+        );
+        process::exit(1);
     }
 }
 
+/// Reports the findings of the static checks, exits with an error if there are any.
+fn verify(file_name: &str) {
+    let program = load_or_exit(file_name);
 
-fn build_commands() -> HashMap<String, fn(Option<&&str>, &mut VMState)> {
-    let mut commands: HashMap<String, fn(Option<&&str>, &mut VMState)> = HashMap::new();
-    commands.insert("PUSH".to_string(), ex_push);
-    commands.insert("POP".to_string(), ex_pop);
-    commands.insert("TOP".to_string(), ex_top);
-    commands.insert("ADD".to_string(), ex_add);
-    commands.insert("SUB".to_string(), ex_sub);
-    commands.insert("DIV".to_string(), ex_div);
-    commands.insert("MUL".to_string(), ex_mul);
-    commands.insert("LOAD".to_string(), ex_load);
-    commands.insert("JMP".to_string(), ex_jmp);
-    commands.insert("JZ".to_string(), ex_jz);
-    commands.insert("JNZ".to_string(), ex_jnz);
-    commands.insert("PRINT".to_string(), ex_print);
+    let errors = verify::verify(&program.code);
+    for error in &errors {
+        let (location, context) = program.locate(file_name, error.ip());
+        eprintln!("{}: error: {}", location, error);
+        eprintln!("    {}", context);
+    }
+    if !errors.is_empty() {
+        process::exit(1);
+    }
+}
 
-    commands
+fn asm(input: &str, output: &str) {
+    let program = load_or_exit(input);
+    write_or_exit(
+        output,
+        bytecode::encode(
+            &program.code,
+            &program.labels,
+            &program.slots,
+            &program.data,
+            &program.hosts,
+        ),
+    );
 }
 
-fn read_program(file_name : &str) -> Vec<String> {
-    let content = read_to_string(file_name).expect("Could not open the specified file!");
-    content.trim().split("\n").map(|x| x.to_ascii_uppercase()).collect()
+fn disasm(input: &str, output: Option<&str>) {
+    let program = load_or_exit(input);
+    let text = bytecode::disassemble(
+        &program.code,
+        &program.labels,
+        &program.slots,
+        &program.data,
+        &program.hosts,
+    );
+    match output {
+        Some(output) => write_or_exit(output, text),
+        None => print!("{}", text),
+    }
 }
 
-fn main() {
-    let args : Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("Require program file! Please specify a file.");
+fn compile_to(input: &str, output: Option<&str>) {
+    let content = fs::read_to_string(input).unwrap_or_else(|e| {
+        eprintln!("{}: could not open the file: {}", input, e);
+        process::exit(1);
+    });
+    let compiled = compile(input, &content).unwrap_or_else(|errors| {
+        for error in errors {
+            eprintln!("{}", error);
+        }
         process::exit(1);
+    });
+    let text: String = compiled
+        .assembly
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect();
+    match output {
+        Some(output) => write_or_exit(output, text),
+        None => print!("{}", text),
     }
+}
 
-    let commands = build_commands();
-    let program = read_program(&args[1]);
+/// Command line options shared by the commands.
+#[derive(Default)]
+struct Options {
+    limits: Limits,
+    trace: bool,
+    profile: bool,
+    optimize: bool,
+    emit_optimized: bool,
+}
+
+/// Splits the `--flag` and `--option value` arguments off the others.
+fn parse_options(args: &[String]) -> Result<(Options, Vec<&str>), String> {
+    let mut options = Options::default();
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--trace" => options.trace = true,
+            "--profile" => options.profile = true,
+            "--optimize" => options.optimize = true,
+            "--emit-optimized" => options.emit_optimized = true,
+            _ if arg.starts_with("--") => {
+                let value = args.next().ok_or(format!("{} requires a value", arg))?;
+                let invalid = |_| format!("invalid value '{}' for {}", value, arg);
+                let limits = &mut options.limits;
+                match arg.as_str() {
+                    "--max-call-depth" => limits.max_call_depth = value.parse().map_err(invalid)?,
+                    "--max-stack" => limits.max_stack = value.parse().map_err(invalid)?,
+                    "--memory" => limits.memory = value.parse().map_err(invalid)?,
+                    "--max-steps" => limits.max_steps = Some(value.parse().map_err(invalid)?),
+                    "--timeout" => {
+                        let seconds = value
+                            .parse::<f64>()
+                            .ok()
+                            .filter(|s| *s >= 0.0 && s.is_finite());
+                        let seconds =
+                            seconds.ok_or(format!("invalid value '{}' for {}", value, arg))?;
+                        limits.timeout = Some(Duration::from_secs_f64(seconds));
+                    }
+                    _ => return Err(format!("unknown option {}", arg)),
+                }
+            }
+            _ => positional.push(arg.as_str()),
+        }
+    }
+    Ok((options, positional))
+}
 
-    let mut vm_state = VMState::new();
-    while vm_state.ip < program.len() {
-        let instruction : Vec<&str> = program[vm_state.ip].split_ascii_whitespace().collect();
-        let arg = instruction.get(1);
-        if let Some(cmd) = commands.get(&instruction[0].to_string()) {
-            cmd(arg, &mut vm_state);
-        } else {
-            panic!(format!("Invalid instruction at line {}: '{}'", vm_state.ip, instruction[0]));
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (options, args) = parse_options(&args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        println!("{}", USAGE);
+        process::exit(1);
+    });
+    match args[..] {
+        ["asm", input, output] => asm(input, output),
+        ["disasm", input] => disasm(input, None),
+        ["disasm", input, output] => disasm(input, Some(output)),
+        ["compile", input] => compile_to(input, None),
+        ["compile", input, output] => compile_to(input, Some(output)),
+        ["debug", file] => debugger::debug(file, &load_or_exit(file), options.limits),
+        ["verify", file] => verify(file),
+        ["run", file] => run(file, &options),
+        [file]
+            if !matches!(
+                file,
+                "asm" | "compile" | "disasm" | "debug" | "run" | "verify"
+            ) =>
+        {
+            run(file, &options)
+        }
+        _ => {
+            println!("{}", USAGE);
+            process::exit(1);
         }
     }
 }
@@ -0,0 +1,232 @@
+use std::collections::HashSet;
+
+use crate::instr::Instr;
+
+/// A program after `optimize` and how it relates to the original.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Optimized {
+    pub code: Vec<Instr>,
+    /// New index of every original instruction plus one entry for the end of the program,
+    /// removed instructions map to the instruction following them.
+    pub index: Vec<usize>,
+    /// Original index of every new instruction.
+    pub origin: Vec<usize>,
+}
+
+/// Replacement of the adjacent instructions `a` and `b`: `Some(None)` removes both,
+/// `Some(Some(instr))` merges them into `instr`.
+fn combine(a: Instr, b: Instr) -> Option<Option<Instr>> {
+    match (a, b) {
+        (Instr::Push, Instr::Pop) => Some(None),
+        (Instr::Load(value), Instr::Pop) => Some(Some(Instr::Set(value))),
+        // both copy the top of the stack into reg
+        (Instr::Dup, Instr::Pop) | (Instr::Pop, Instr::Push) => Some(Some(Instr::Top)),
+        (Instr::Store(to), Instr::Fetch(from)) if to == from => Some(Some(Instr::Store(to))),
+        // the value set is overwritten before it is used
+        (Instr::Set(_), Instr::Set(_))
+        | (Instr::Set(_), Instr::Pop)
+        | (Instr::Set(_), Instr::Top)
+        | (Instr::Set(_), Instr::Fetch(_))
+        | (Instr::Set(_), Instr::Read) => Some(Some(b)),
+        _ => None,
+    }
+}
+
+/// Final destination of a jump to `target`, following chains of `JMP`s.
+fn thread(code: &[Instr], mut target: usize) -> usize {
+    // a cycle of jumps never gets anywhere, stop after visiting every instruction
+    for _ in 0..code.len() {
+        match code.get(target) {
+            Some(Instr::Jmp(next)) if *next != target => target = *next,
+            _ => break,
+        }
+    }
+    target
+}
+
+/// One round over `code`, `None` if nothing changed.
+fn pass(code: &[Instr]) -> Option<Optimized> {
+    let targets: HashSet<usize> = code.iter().filter_map(Instr::target).collect();
+    let mut out = Vec::new();
+    let mut index = Vec::with_capacity(code.len() + 1);
+    let mut origin = Vec::new();
+    let mut reachable = true;
+
+    let mut ip = 0;
+    while ip < code.len() {
+        reachable |= targets.contains(&ip);
+        let instr = match code[ip] {
+            Instr::Jmp(target) => Instr::Jmp(thread(code, target)),
+            Instr::Jz(target) => Instr::Jz(thread(code, target)),
+            Instr::Jnz(target) => Instr::Jnz(thread(code, target)),
+            Instr::Call(target) => Instr::Call(thread(code, target)),
+            instr => instr,
+        };
+        // conditional jumps stay, they fail on a non-integer register
+        let jumps_to_next = instr == Instr::Jmp(ip + 1);
+        if !reachable || jumps_to_next {
+            index.push(out.len());
+            ip += 1;
+            continue;
+        }
+        // the second instruction of a pair must not be entered by a jump
+        let next = code.get(ip + 1).filter(|_| !targets.contains(&(ip + 1)));
+        if let Some(replacement) = next.and_then(|&next| combine(instr, next)) {
+            index.extend([out.len(), out.len()]);
+            if let Some(replacement) = replacement {
+                origin.push(ip);
+                out.push(replacement);
+            }
+            ip += 2;
+            continue;
+        }
+
+        index.push(out.len());
+        origin.push(ip);
+        out.push(instr);
+        reachable = !matches!(instr, Instr::Jmp(_) | Instr::Ret);
+        ip += 1;
+    }
+    index.push(out.len());
+
+    for instr in &mut out {
+        match instr {
+            Instr::Jmp(target) | Instr::Jz(target) | Instr::Jnz(target) | Instr::Call(target) => {
+                *target = index[*target]
+            }
+            _ => {}
+        }
+    }
+    if out == code {
+        return None;
+    }
+    Some(Optimized {
+        code: out,
+        index,
+        origin,
+    })
+}
+
+/// Peephole optimization: merges or removes redundant instruction pairs like `PUSH` `POP`,
+/// threads jumps to jumps, and drops unconditional jumps to the next instruction and
+/// unreachable code.
+/// Jump and call targets are moved along, the optimized program behaves the same apart from
+/// instruction counts and the `ip` of errors.
+///
+/// Programs with jump targets out of range are left as they are.
+pub fn optimize(code: &[Instr]) -> Optimized {
+    let mut result = Optimized {
+        code: code.to_vec(),
+        index: (0..=code.len()).collect(),
+        origin: (0..code.len()).collect(),
+    };
+    if code
+        .iter()
+        .filter_map(Instr::target)
+        .any(|target| target > code.len())
+    {
+        return result;
+    }
+
+    while let Some(round) = pass(&result.code) {
+        for new in &mut result.index {
+            *new = round.index[*new];
+        }
+        result.origin = round.origin.iter().map(|&ip| result.origin[ip]).collect();
+        result.code = round.code;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::MemoryIo;
+    use crate::value::Value;
+    use crate::vm::{Limits, VMState};
+    use crate::{assembler, compiler, instr};
+
+    fn parse(source: &str) -> (Vec<Instr>, Vec<u8>) {
+        let lines: Vec<String> = source.lines().map(String::from).collect();
+        let program = assembler::assemble(&lines).unwrap();
+        (instr::parse(&program.instructions).unwrap(), program.data)
+    }
+
+    /// Runs the program before and after optimizing it and compares the results.
+    fn same_behaviour(code: &[Instr], data: &[u8], input: &str) -> Optimized {
+        let optimized = optimize(code);
+        let run = |code: &[Instr]| {
+            let mut vm_state = VMState::with_io(Limits::default(), MemoryIo::new(input));
+            vm_state.data = data.to_vec();
+            let ok = vm_state.run(code).is_ok();
+            let output = vm_state.io.output();
+            (ok, output, vm_state.reg, vm_state.stack, vm_state.memory)
+        };
+        assert_eq!(run(&optimized.code), run(code));
+        optimized
+    }
+
+    #[test]
+    fn pairs() {
+        let (code, data) = parse("LOAD 3\nPOP\nPUSH\nPOP\nSTORE 0\nFETCH 0\nPUSH\nDUP\nPOP\nPRINT");
+        let optimized = same_behaviour(&code, &data, "");
+        assert_eq!(
+            optimized.code,
+            [
+                Instr::Set(Value::Int(3)),
+                Instr::Store(0),
+                Instr::Push,
+                Instr::Top,
+                Instr::Print
+            ]
+        );
+        assert_eq!(optimized.origin, [0, 4, 6, 7, 9]);
+        assert_eq!(optimized.index, [0, 0, 1, 1, 1, 1, 2, 3, 3, 4, 5]);
+    }
+
+    #[test]
+    fn jumps() {
+        // the pair at a jump target stays, jumps are threaded and the dead code is dropped
+        let source = "READ\nJZ A\nJMP B\nPRINT\nA: JMP C\nC: PUSH\nB: POP\nPRINT";
+        let (code, data) = parse(source);
+        for input in ["0", "1"] {
+            same_behaviour(&code, &data, input);
+        }
+        assert_eq!(
+            optimize(&code).code,
+            [
+                Instr::Read,
+                Instr::Jz(3),
+                Instr::Jmp(4),
+                Instr::Push,
+                Instr::Pop,
+                Instr::Print
+            ]
+        );
+        assert_eq!(optimize(&[Instr::Jmp(0)]).code, [Instr::Jmp(0)]);
+
+        // JZ to the next instruction still checks the register type
+        let (code, data) = parse("LOAD 1.5\nPOP\nJZ NEXT\nNEXT: PRINT");
+        let optimized = same_behaviour(&code, &data, "");
+        assert_eq!(optimized.code[1], Instr::Jz(2));
+    }
+
+    #[test]
+    fn compiled_programs() {
+        let sources = [
+            "n = 10; sum = 0;\nwhile n { sum = sum + n; n = n - 1; }\nprint sum;",
+            "x = 7;\nif x - 7 { print 1; } else if x { print -(x * 3) / 2; } else { print 0; }",
+        ];
+        for source in sources {
+            let compiled = compiler::compile(source).unwrap();
+            let program = assembler::assemble(&compiled.assembly).unwrap();
+            let code = instr::parse(&program.instructions).unwrap();
+            let optimized = same_behaviour(&code, &program.data, "");
+            assert!(optimized.code.len() < code.len());
+        }
+
+        let source = "PRINTS \"a\"\nLOAD 2\nPOP\nCALL F\nPRINT\nJMP END\nF: PUSH\nADD\nRET\nEND:";
+        let (code, data) = parse(source);
+        same_behaviour(&code, &data, "");
+    }
+}
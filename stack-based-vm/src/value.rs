@@ -0,0 +1,101 @@
+use std::fmt;
+
+/// A value in `reg`, on the stack or in memory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+        }
+    }
+
+    /// Parses a literal: a decimal or hexadecimal (`0x1f`) integer, or a float like `1.5`,
+    /// `-2e3` or `inf`.
+    pub fn parse(literal: &str) -> Option<Value> {
+        let (negative, digits) = match literal.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, literal),
+        };
+        if let Some(hex) = digits
+            .strip_prefix("0x")
+            .or_else(|| digits.strip_prefix("0X"))
+        {
+            // parsed with the sign so that the minimum fits
+            let sign = if negative { "-" } else { "" };
+            return i64::from_str_radix(&format!("{}{}", sign, hex), 16)
+                .ok()
+                .filter(|_| !hex.starts_with(['+', '-']))
+                .map(Value::Int);
+        }
+        if let Ok(n) = literal.parse() {
+            return Some(Value::Int(n));
+        }
+        literal.parse().ok().map(Value::Float)
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::Int(0)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(x: f64) -> Self {
+        Value::Float(x)
+    }
+}
+
+/// Integers print as usual, floats always with a fraction or exponent (`2.0`), so that the
+/// text parses back to the same value.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{:?}", x),
+        }
+    }
+}
+
+/// Formats values as a list like `[1, 2.5]`.
+pub fn list(values: &[Value]) -> String {
+    let items: Vec<String> = values.iter().map(Value::to_string).collect();
+    format!("[{}]", items.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals() {
+        assert_eq!(Value::parse("42"), Some(Value::Int(42)));
+        assert_eq!(Value::parse("-0x1F"), Some(Value::Int(-31)));
+        assert_eq!(Value::parse("0x8000000000000000"), None);
+        assert_eq!(
+            Value::parse("-0x8000000000000000"),
+            Some(Value::Int(i64::MIN))
+        );
+        assert_eq!(Value::parse("0x-1"), None);
+        assert_eq!(Value::parse("2.5"), Some(Value::Float(2.5)));
+        assert_eq!(Value::parse("-1E3"), Some(Value::Float(-1000.0)));
+        assert_eq!(Value::parse("INF"), Some(Value::Float(f64::INFINITY)));
+        assert_eq!(Value::parse("x"), None);
+
+        for value in [Value::Int(-7), Value::Float(2.0), Value::Float(1e300)] {
+            assert_eq!(Value::parse(&value.to_string()), Some(value));
+        }
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::instr::Instr;
+
+/// Problem found by `verify`, `ip` is the index of the offending instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    BadTarget {
+        ip: usize,
+        op: &'static str,
+        target: usize,
+    },
+    /// The stack holds `depth` values on some path, but the instruction needs `need`.
+    Underflow {
+        ip: usize,
+        op: &'static str,
+        depth: usize,
+        need: usize,
+    },
+    /// Two paths reach `ip` with different stack depths. Within the procedure starting at
+    /// `procedure` the depths are relative to its entry.
+    DepthMismatch {
+        ip: usize,
+        procedure: Option<usize>,
+        first: isize,
+        second: isize,
+    },
+    ReturnOutsideCall {
+        ip: usize,
+    },
+}
+
+impl VerifyError {
+    pub fn ip(&self) -> usize {
+        match self {
+            VerifyError::BadTarget { ip, .. }
+            | VerifyError::Underflow { ip, .. }
+            | VerifyError::DepthMismatch { ip, .. }
+            | VerifyError::ReturnOutsideCall { ip } => *ip,
+        }
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::BadTarget { ip, op, target } => {
+                write!(f, "[{}:{}] jump target {} is out of range", op, ip, target)
+            }
+            VerifyError::Underflow {
+                ip,
+                op,
+                depth,
+                need,
+            } => write!(
+                f,
+                "[{}:{}] stack underflow, needs {} values but a path reaches it with {}",
+                op, ip, need, depth
+            ),
+            VerifyError::DepthMismatch {
+                ip,
+                procedure,
+                first,
+                second,
+            } => {
+                write!(
+                    f,
+                    "[{}] inconsistent stack depth, {} on one path and {} on another",
+                    ip, first, second
+                )?;
+                match procedure {
+                    Some(entry) => write!(f, " (relative to the procedure at {})", entry),
+                    None => Ok(()),
+                }
+            }
+            VerifyError::ReturnOutsideCall { ip } => {
+                write!(f, "[RET:{}] return outside of a procedure", ip)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Stack behaviour of a procedure, as seen by its callers.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct Procedure {
+    /// Change of the stack depth from the call to the return, unknown until a `RET` is reached.
+    effect: Option<isize>,
+    /// Number of values the procedure takes from the caller's stack.
+    need: usize,
+}
+
+/// Walks the control-flow graph from `entry`, the start of the program if `None`, and tracks
+/// the stack depth relative to it. Returns the stack behaviour of the walked code.
+///
+/// Every instruction keeps the depth it is first reached with, later paths with a different
+/// depth are reported. A `CALL` continues with the effect of the procedure if it is known.
+fn analyze(
+    code: &[Instr],
+    entry: Option<usize>,
+    procedures: &BTreeMap<usize, Procedure>,
+    errors: &mut Vec<VerifyError>,
+) -> Procedure {
+    let mut report = |error: VerifyError| {
+        if !errors.contains(&error) {
+            errors.push(error);
+        }
+    };
+    let mut result = Procedure::default();
+    let mut depths: Vec<Option<isize>> = vec![None; code.len()];
+    let mut work = vec![(entry.unwrap_or(0), 0)];
+
+    while let Some((ip, depth)) = work.pop() {
+        // reaching the end finishes the program
+        let instr = match code.get(ip) {
+            Some(&instr) => instr,
+            None => continue,
+        };
+        match depths[ip] {
+            Some(first) if first != depth => {
+                report(VerifyError::DepthMismatch {
+                    ip,
+                    procedure: entry,
+                    first,
+                    second: depth,
+                });
+                continue;
+            }
+            Some(_) => continue,
+            None => depths[ip] = Some(depth),
+        }
+
+        let (need, effect) = match instr {
+            Instr::Call(target) => {
+                let procedure = procedures.get(&target).copied().unwrap_or_default();
+                (procedure.need, procedure.effect)
+            }
+            Instr::Ret => {
+                match (entry, result.effect) {
+                    (None, _) => report(VerifyError::ReturnOutsideCall { ip }),
+                    (Some(_), None) => result.effect = Some(depth),
+                    (Some(_), Some(first)) if first != depth => {
+                        report(VerifyError::DepthMismatch {
+                            ip,
+                            procedure: entry,
+                            first,
+                            second: depth,
+                        })
+                    }
+                    (Some(_), Some(_)) => {}
+                }
+                continue;
+            }
+            _ => {
+                let (need, effect) = instr.stack_effect();
+                (need, Some(effect))
+            }
+        };
+        let missing = need as isize - depth;
+        if missing > 0 {
+            match entry {
+                // the stack of the program starts empty, so this path fails
+                None => {
+                    report(VerifyError::Underflow {
+                        ip,
+                        op: instr.mnemonic(),
+                        depth: depth as usize,
+                        need,
+                    });
+                    continue;
+                }
+                // a procedure takes the missing values from its caller
+                Some(_) => result.need = result.need.max(missing as usize),
+            }
+        }
+
+        // a procedure that never returns ends the path
+        let depth = match effect {
+            Some(effect) => depth + effect,
+            None => continue,
+        };
+        match instr {
+            Instr::Jmp(target) => work.push((target, depth)),
+            Instr::Jz(target) | Instr::Jnz(target) => {
+                work.push((ip + 1, depth));
+                work.push((target, depth));
+            }
+            _ => work.push((ip + 1, depth)),
+        }
+    }
+    result
+}
+
+/// Checks `code` before it runs: the jump and call targets must lie within the program, no
+/// path may pop more values than it pushed, and all paths to an instruction must agree on the
+/// stack depth. Returns everything found, an empty list for a correct program.
+pub fn verify(code: &[Instr]) -> Vec<VerifyError> {
+    let mut errors = Vec::new();
+    for (ip, instr) in code.iter().enumerate() {
+        match instr.target() {
+            Some(target) if target > code.len() => errors.push(VerifyError::BadTarget {
+                ip,
+                op: instr.mnemonic(),
+                target,
+            }),
+            _ => {}
+        }
+    }
+    // the control-flow graph is only known with valid targets
+    if !errors.is_empty() {
+        return errors;
+    }
+
+    let entries: BTreeSet<usize> = code
+        .iter()
+        .filter_map(|instr| match instr {
+            Instr::Call(target) => Some(*target),
+            _ => None,
+        })
+        .collect();
+    let mut procedures: BTreeMap<usize, Procedure> = entries
+        .iter()
+        .map(|&entry| (entry, Procedure::default()))
+        .collect();
+    // procedures calling each other need several rounds, recursion that keeps growing the
+    // stack would never settle
+    for _ in 0..=procedures.len() {
+        let previous = procedures.clone();
+        for &entry in &entries {
+            let procedure = analyze(code, Some(entry), &procedures, &mut Vec::new());
+            procedures.insert(entry, procedure);
+        }
+        if procedures == previous {
+            break;
+        }
+    }
+
+    analyze(code, None, &procedures, &mut errors);
+    for &entry in &entries {
+        analyze(code, Some(entry), &procedures, &mut errors);
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler, instr};
+
+    fn check(source: &str) -> Vec<VerifyError> {
+        let lines: Vec<String> = source.lines().map(String::from).collect();
+        let program = assembler::assemble(&lines).unwrap();
+        verify(&instr::parse(&program.instructions).unwrap())
+    }
+
+    #[test]
+    fn correct_programs() {
+        assert_eq!(
+            check("LOAD 3\nPOP\nLOOP:\nPUSH\nLOAD 1\nPOP\nSUB\nJNZ LOOP"),
+            []
+        );
+        // QUAD calls TWICE, which is defined later and takes one value from the caller
+        let source = "LOAD 3\nPOP\nCALL QUAD\nJMP END\n\
+                      TWICE: PUSH\nADD\nRET\n\
+                      QUAD: CALL TWICE\nCALL TWICE\nRET\nEND:";
+        assert_eq!(check(source), []);
+        assert_eq!(
+            check("LOAD 1\nCALL SUM\nPRINT\nJMP END\nSUM: ADD\nRET\nEND:"),
+            []
+        );
+    }
+
+    #[test]
+    fn targets() {
+        assert_eq!(
+            verify(&[Instr::Jz(1), Instr::Jmp(3)]),
+            [VerifyError::BadTarget {
+                ip: 1,
+                op: "JMP",
+                target: 3
+            }]
+        );
+        // jumping right behind the last instruction ends the program
+        assert_eq!(verify(&[Instr::Jmp(1)]), []);
+        assert_eq!(check("RET"), [VerifyError::ReturnOutsideCall { ip: 0 }]);
+    }
+
+    #[test]
+    fn underflow() {
+        // only the path skipping the LOAD underflows
+        assert_eq!(
+            check("READ\nJZ SKIP\nLOAD 1\nSKIP: POP"),
+            [
+                VerifyError::Underflow {
+                    ip: 3,
+                    op: "POP",
+                    depth: 0,
+                    need: 1
+                },
+                VerifyError::DepthMismatch {
+                    ip: 3,
+                    procedure: None,
+                    first: 0,
+                    second: 1
+                }
+            ]
+        );
+        // the procedure needs two values from its caller
+        assert_eq!(
+            check("LOAD 1\nCALL F\nJMP END\nF: SWAP\nRET\nEND:"),
+            [VerifyError::Underflow {
+                ip: 1,
+                op: "CALL",
+                depth: 1,
+                need: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn depth_mismatch() {
+        assert_eq!(
+            check("LOOP: PUSH\nJMP LOOP"),
+            [VerifyError::DepthMismatch {
+                ip: 0,
+                procedure: None,
+                first: 0,
+                second: 1
+            }]
+        );
+        assert_eq!(
+            check("CALL F\nJMP END\nF: JZ DONE\nPUSH\nDONE: RET\nEND:"),
+            [VerifyError::DepthMismatch {
+                ip: 4,
+                procedure: Some(2),
+                first: 0,
+                second: 1
+            }]
+        );
+    }
+}
@@ -0,0 +1,1001 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use crate::error::VmError;
+use crate::instr::Instr;
+use crate::io::{Io, StdIo};
+use crate::value::Value;
+
+/// Number of cells addressable by `STORE` and `FETCH`.
+pub const MEMORY_SIZE: usize = 256;
+
+/// Resource limits of a VM, for running programs from untrusted sources.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Maximum number of nested `CALL`s.
+    pub max_call_depth: usize,
+    /// Maximum number of values on the data stack.
+    pub max_stack: usize,
+    /// Number of memory cells available to `STORE` and `FETCH`, at most `MEMORY_SIZE`.
+    pub memory: usize,
+    /// Maximum number of executed instructions, unlimited if `None`.
+    pub max_steps: Option<u64>,
+    /// Maximum wall-clock time of a single `run`, unlimited if `None`.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_call_depth: 1024,
+            max_stack: 1 << 20,
+            memory: MEMORY_SIZE,
+            max_steps: None,
+            timeout: None,
+        }
+    }
+}
+
+/// The clock is only read every that many steps.
+const TIMEOUT_INTERVAL: u64 = 1024;
+
+/// Native function called by `HOST name`. It works on the VM like an instruction, e.g. takes
+/// its arguments from `reg` and the stack and leaves its result in `reg`, but must not change
+/// `ip`. An `Err` stops the program.
+pub type HostFn<I> = Box<dyn FnMut(&mut VMState<I>) -> Result<(), String>>;
+
+pub struct VMState<I = StdIo> {
+    pub ip: usize,
+    pub reg: Value,
+    pub stack: Vec<Value>,
+    pub memory: Vec<Value>,
+    /// Return addresses of the active `CALL`s, separate from the data stack.
+    pub call_stack: Vec<usize>,
+    /// Read-only data segment with the NUL terminated strings of `PRINTS`.
+    pub data: Vec<u8>,
+    /// Number of instructions executed so far.
+    pub steps: u64,
+    /// Host function names of the program, indexed by the operand of `HOST`.
+    pub hosts: Vec<String>,
+    /// Functions bound with `bind_host` by their uppercase name, looked up when `HOST` runs.
+    functions: HashMap<String, HostFn<I>>,
+    pub limits: Limits,
+    pub io: I,
+}
+
+impl Default for VMState {
+    fn default() -> Self {
+        Self::with_limits(Limits::default())
+    }
+}
+
+impl VMState {
+    pub fn with_limits(limits: Limits) -> Self {
+        Self::with_io(limits, StdIo::default())
+    }
+}
+
+impl<I: Io> VMState<I> {
+    pub fn with_io(limits: Limits, io: I) -> Self {
+        Self {
+            ip: 0,
+            reg: Value::default(),
+            stack: Vec::new(),
+            memory: vec![Value::default(); limits.memory.min(MEMORY_SIZE)],
+            call_stack: Vec::new(),
+            data: Vec::new(),
+            steps: 0,
+            hosts: Vec::new(),
+            functions: HashMap::new(),
+            limits,
+            io,
+        }
+    }
+
+    fn push(&mut self, op: &'static str, value: Value) -> Result<(), VmError> {
+        if self.stack.len() >= self.limits.max_stack {
+            return Err(VmError::StackOverflow {
+                ip: self.ip,
+                op,
+                max: self.limits.max_stack,
+            });
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn pop(&mut self, op: &'static str) -> Result<Value, VmError> {
+        self.stack
+            .pop()
+            .ok_or(VmError::StackUnderflow { ip: self.ip, op })
+    }
+
+    /// The value `depth` elements below the top of the stack.
+    fn peek(&self, depth: usize, op: &'static str) -> Result<Value, VmError> {
+        self.stack
+            .len()
+            .checked_sub(depth + 1)
+            .map(|idx| self.stack[idx])
+            .ok_or(VmError::StackUnderflow { ip: self.ip, op })
+    }
+
+    /// `reg` if it holds an integer.
+    fn int(&self, op: &'static str) -> Result<i64, VmError> {
+        match self.reg {
+            Value::Int(n) => Ok(n),
+            other => Err(VmError::WrongType {
+                ip: self.ip,
+                op,
+                expected: "int",
+                found: other.type_name(),
+            }),
+        }
+    }
+
+    /// Pops the left operand of a binary operation, `reg` is the right one. Both operands must
+    /// have the same type.
+    fn operands(&mut self, op: &'static str) -> Result<(Value, Value), VmError> {
+        let left = self.pop(op)?;
+        if mem::discriminant(&left) != mem::discriminant(&self.reg) {
+            return Err(VmError::TypeMismatch {
+                ip: self.ip,
+                op,
+                left: left.type_name(),
+                right: self.reg.type_name(),
+            });
+        }
+        Ok((left, self.reg))
+    }
+
+    fn arith(
+        &mut self,
+        op: &'static str,
+        int: fn(i64, i64) -> Option<i64>,
+        float: fn(f64, f64) -> f64,
+    ) -> Result<(), VmError> {
+        self.reg = match self.operands(op)? {
+            (Value::Int(a), Value::Int(b)) => {
+                Value::Int(int(a, b).ok_or(VmError::Overflow { ip: self.ip, op })?)
+            }
+            (Value::Float(a), Value::Float(b)) => Value::Float(float(a, b)),
+            _ => unreachable!("operands of different types"),
+        };
+        self.ip += 1;
+        Ok(())
+    }
+
+    /// Like `arith`, the result is 1 if the comparison holds and 0 otherwise.
+    fn compare(
+        &mut self,
+        op: &'static str,
+        int: fn(&i64, &i64) -> bool,
+        float: fn(&f64, &f64) -> bool,
+    ) -> Result<(), VmError> {
+        let holds = match self.operands(op)? {
+            (Value::Int(a), Value::Int(b)) => int(&a, &b),
+            (Value::Float(a), Value::Float(b)) => float(&a, &b),
+            _ => unreachable!("operands of different types"),
+        };
+        self.reg = Value::Int(i64::from(holds));
+        self.ip += 1;
+        Ok(())
+    }
+
+    /// Like `arith` for operations defined on integers only.
+    fn bitwise(&mut self, op: &'static str, f: fn(i64, i64) -> Option<i64>) -> Result<(), VmError> {
+        self.reg = match self.operands(op)? {
+            (Value::Int(a), Value::Int(b)) => {
+                Value::Int(f(a, b).ok_or(VmError::Overflow { ip: self.ip, op })?)
+            }
+            (other, _) => {
+                return Err(VmError::WrongType {
+                    ip: self.ip,
+                    op,
+                    expected: "int",
+                    found: other.type_name(),
+                })
+            }
+        };
+        self.ip += 1;
+        Ok(())
+    }
+
+    /// Fails for an integer division by zero before the stack is touched.
+    fn check_divisor(&self, op: &'static str) -> Result<(), VmError> {
+        let value = self.peek(0, op)?;
+        if let (Value::Int(_), Value::Int(0)) = (value, self.reg) {
+            return Err(VmError::DivideByZero { ip: self.ip });
+        }
+        Ok(())
+    }
+
+    fn cell(&mut self, op: &'static str, addr: usize) -> Result<&mut Value, VmError> {
+        let ip = self.ip;
+        let max = self.memory.len();
+        self.memory.get_mut(addr).ok_or(if addr < MEMORY_SIZE {
+            VmError::MemoryLimit { ip, op, addr, max }
+        } else {
+            VmError::BadAddress { ip, op, addr }
+        })
+    }
+
+    fn write(&mut self, op: &'static str, bytes: &[u8]) -> Result<(), VmError> {
+        let ip = self.ip;
+        self.io.write(bytes).map_err(|e| VmError::Io {
+            ip,
+            op,
+            message: e.to_string(),
+        })
+    }
+
+    fn read(&mut self) -> Result<Value, VmError> {
+        let ip = self.ip;
+        let word = self.io.read_word().map_err(|e| VmError::Io {
+            ip,
+            op: "READ",
+            message: e.to_string(),
+        })?;
+        let word = word.ok_or(VmError::BadInput { ip, input: None })?;
+        Value::parse(&word).ok_or(VmError::BadInput {
+            ip,
+            input: Some(word),
+        })
+    }
+
+    /// Binds `f` to the host function `name` (case insensitive), replacing an earlier one.
+    /// The function is kept even if the current program does not use it, the return value
+    /// tells whether it does.
+    pub fn bind_host(&mut self, name: &str, f: HostFn<I>) -> bool {
+        self.functions.insert(name.to_ascii_uppercase(), f);
+        self.hosts
+            .iter()
+            .any(|host| host.eq_ignore_ascii_case(name))
+    }
+
+    fn host_name(&self, index: usize) -> String {
+        match self.hosts.get(index) {
+            Some(name) => name.clone(),
+            None => format!("#{}", index),
+        }
+    }
+
+    fn call_host(&mut self, index: usize) -> Result<(), VmError> {
+        let ip = self.ip;
+        let name = self.host_name(index).to_ascii_uppercase();
+        // the function is taken out while it runs, it gets the VM itself
+        let mut f = match self.functions.remove(&name) {
+            Some(f) => f,
+            None => {
+                return Err(VmError::UnboundHost {
+                    ip,
+                    name: self.host_name(index),
+                })
+            }
+        };
+        let result = f(self);
+        // unless the function bound another one to its name meanwhile
+        self.functions.entry(name).or_insert(f);
+        self.ip = ip + 1;
+        result.map_err(|message| VmError::Host {
+            ip,
+            name: self.host_name(index),
+            message,
+        })?;
+        // the function may push any number of values
+        if self.stack.len() > self.limits.max_stack {
+            return Err(VmError::StackOverflow {
+                ip,
+                op: "HOST",
+                max: self.limits.max_stack,
+            });
+        }
+        Ok(())
+    }
+
+    fn jump_if(&mut self, cond: bool, target: usize) {
+        if cond {
+            self.ip = target;
+        } else {
+            self.ip += 1;
+        }
+    }
+
+    /// Executes the single instruction `instr` located at `self.ip`.
+    pub fn step(&mut self, instr: Instr) -> Result<(), VmError> {
+        match instr {
+            Instr::Print => {
+                self.write("PRINT", format!("{}\n", self.reg).as_bytes())?;
+                self.ip += 1;
+            }
+            Instr::PrintC => {
+                let value = self.int("PRINTC")?;
+                let c = u32::try_from(value)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or(VmError::BadChar { ip: self.ip, value })?;
+                self.write("PRINTC", c.to_string().as_bytes())?;
+                self.ip += 1;
+            }
+            Instr::PrintS(offset) => {
+                let text = self.data.get(offset..).unwrap_or_default();
+                let len = text
+                    .iter()
+                    .position(|&b| b == 0)
+                    .ok_or(VmError::BadAddress {
+                        ip: self.ip,
+                        op: "PRINTS",
+                        addr: offset,
+                    })?;
+                let text = text[..len].to_vec();
+                self.write("PRINTS", &text)?;
+                self.ip += 1;
+            }
+            Instr::Read => {
+                self.reg = self.read()?;
+                self.ip += 1;
+            }
+            Instr::Push => {
+                self.push("PUSH", self.reg)?;
+                self.ip += 1;
+            }
+            Instr::Pop => {
+                self.reg = self.pop("POP")?;
+                self.ip += 1;
+            }
+            Instr::Top => {
+                self.reg = self.peek(0, "TOP")?;
+                self.ip += 1;
+            }
+            Instr::Dup => {
+                let value = self.peek(0, "DUP")?;
+                self.push("DUP", value)?;
+                self.ip += 1;
+            }
+            Instr::Over => {
+                let value = self.peek(1, "OVER")?;
+                self.push("OVER", value)?;
+                self.ip += 1;
+            }
+            Instr::Swap => {
+                let len = self.stack.len();
+                if len < 2 {
+                    return Err(VmError::StackUnderflow {
+                        ip: self.ip,
+                        op: "SWAP",
+                    });
+                }
+                self.stack.swap(len - 1, len - 2);
+                self.ip += 1;
+            }
+            Instr::Add => self.arith("ADD", i64::checked_add, |a, b| a + b)?,
+            Instr::Sub => self.arith("SUB", i64::checked_sub, |a, b| a - b)?,
+            Instr::Mul => self.arith("MUL", i64::checked_mul, |a, b| a * b)?,
+            Instr::Div => {
+                self.check_divisor("DIV")?;
+                // i64::MIN / -1 overflows, floats divide by zero to infinity
+                self.arith("DIV", i64::checked_div, |a, b| a / b)?;
+            }
+            Instr::Mod => {
+                self.check_divisor("MOD")?;
+                // the sign of the result is that of the dividend
+                self.arith("MOD", i64::checked_rem, |a, b| a % b)?;
+            }
+            Instr::Neg => {
+                self.reg = match self.reg {
+                    Value::Int(n) => Value::Int(n.checked_neg().ok_or(VmError::Overflow {
+                        ip: self.ip,
+                        op: "NEG",
+                    })?),
+                    Value::Float(x) => Value::Float(-x),
+                };
+                self.ip += 1;
+            }
+            Instr::Eq => self.compare("EQ", i64::eq, f64::eq)?,
+            Instr::Lt => self.compare("LT", i64::lt, f64::lt)?,
+            Instr::Gt => self.compare("GT", i64::gt, f64::gt)?,
+            Instr::And => self.bitwise("AND", |a, b| Some(a & b))?,
+            Instr::Or => self.bitwise("OR", |a, b| Some(a | b))?,
+            Instr::Xor => self.bitwise("XOR", |a, b| Some(a ^ b))?,
+            Instr::Not => {
+                self.reg = Value::Int(!self.int("NOT")?);
+                self.ip += 1;
+            }
+            // shifting by a negative amount or the width and more overflows, SHR keeps the sign
+            Instr::Shl => self.bitwise("SHL", |a, b| a.checked_shl(u32::try_from(b).ok()?))?,
+            Instr::Shr => self.bitwise("SHR", |a, b| a.checked_shr(u32::try_from(b).ok()?))?,
+            Instr::IntToFloat => {
+                self.reg = Value::Float(self.int("ITOF")? as f64);
+                self.ip += 1;
+            }
+            Instr::FloatToInt => {
+                let value = match self.reg {
+                    Value::Float(x) => x.trunc(),
+                    other => {
+                        return Err(VmError::WrongType {
+                            ip: self.ip,
+                            op: "FTOI",
+                            expected: "float",
+                            found: other.type_name(),
+                        })
+                    }
+                };
+                // NaN fails both comparisons
+                if !(value >= i64::MIN as f64 && value < i64::MAX as f64) {
+                    return Err(VmError::Overflow {
+                        ip: self.ip,
+                        op: "FTOI",
+                    });
+                }
+                self.reg = Value::Int(value as i64);
+                self.ip += 1;
+            }
+            Instr::Load(value) => {
+                self.push("LOAD", value)?;
+                self.ip += 1;
+            }
+            Instr::Host(index) => self.call_host(index)?,
+            Instr::Set(value) => {
+                self.reg = value;
+                self.ip += 1;
+            }
+            Instr::Store(addr) => {
+                let reg = self.reg;
+                *self.cell("STORE", addr)? = reg;
+                self.ip += 1;
+            }
+            Instr::Fetch(addr) => {
+                self.reg = *self.cell("FETCH", addr)?;
+                self.ip += 1;
+            }
+            Instr::Call(target) => {
+                if self.call_stack.len() >= self.limits.max_call_depth {
+                    return Err(VmError::CallDepth {
+                        ip: self.ip,
+                        max: self.limits.max_call_depth,
+                    });
+                }
+                self.call_stack.push(self.ip + 1);
+                self.ip = target;
+            }
+            Instr::Ret => {
+                self.ip = self
+                    .call_stack
+                    .pop()
+                    .ok_or(VmError::ReturnWithoutCall { ip: self.ip })?;
+            }
+            Instr::Jmp(target) => self.ip = target,
+            Instr::Jz(target) => self.jump_if(self.int("JZ")? == 0, target),
+            Instr::Jnz(target) => self.jump_if(self.int("JNZ")? != 0, target),
+        }
+        Ok(())
+    }
+
+    /// Executes `program` until the instruction pointer runs past its end.
+    pub fn run(&mut self, program: &[Instr]) -> Result<(), VmError> {
+        self.run_with(program, |_| true).map(|_| ())
+    }
+
+    /// Like `run`, but calls `hook` before every instruction. Execution pauses (and can be
+    /// resumed by calling `run_with` again) as soon as `hook` returns `false`.
+    ///
+    /// The step budget of the limits covers all calls together, the timeout each call.
+    ///
+    /// Returns whether the program ran to completion.
+    pub fn run_with(
+        &mut self,
+        program: &[Instr],
+        mut hook: impl FnMut(&VMState<I>) -> bool,
+    ) -> Result<bool, VmError> {
+        let max_steps = self.limits.max_steps.unwrap_or(u64::MAX);
+        let deadline = self
+            .limits
+            .timeout
+            .map(|timeout| (Instant::now() + timeout, timeout));
+        while let Some(&instr) = program.get(self.ip) {
+            if !hook(self) {
+                return Ok(false);
+            }
+            let ip = self.ip;
+            if self.steps >= max_steps {
+                return Err(VmError::StepLimit { ip, max: max_steps });
+            }
+            if let Some((deadline, timeout)) = deadline {
+                // `u64::is_multiple_of` needs Rust 1.87
+                #[allow(clippy::manual_is_multiple_of)]
+                if self.steps % TIMEOUT_INTERVAL == 0 && Instant::now() >= deadline {
+                    return Err(VmError::Timeout { ip, timeout });
+                }
+            }
+            self.steps += 1;
+            self.step(instr)?;
+            // jumping right behind the last instruction ends the program
+            if self.ip > program.len() {
+                return Err(VmError::IpOutOfRange {
+                    ip,
+                    target: self.ip,
+                });
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::io::MemoryIo;
+    use crate::{assembler, instr};
+
+    // counts `reg` down from n to 0, five instructions per iteration
+    const COUNTDOWN: &str = "LOAD {n}\nPOP\nLOOP:\nPUSH\nLOAD 1\nPOP\nSUB\nJNZ LOOP";
+    // same with `reg = reg * 3 / 3` in every iteration
+    const ARITH: &str = "LOAD {n}\nPOP\nLOOP:\nPUSH\nLOAD 3\nPOP\nMUL\nPUSH\nLOAD 3\nPOP\nDIV\n\
+                         PUSH\nLOAD 1\nPOP\nSUB\nJNZ LOOP";
+
+    fn assemble(source: &str, n: i32) -> Vec<String> {
+        let lines: Vec<String> = source
+            .replace("{n}", &n.to_string())
+            .lines()
+            .map(String::from)
+            .collect();
+        assembler::assemble(&lines).unwrap().instructions
+    }
+
+    /// The previous interpreter: decode the text of every executed instruction again.
+    fn run_text(vm_state: &mut VMState, program: &[String]) -> Result<(), VmError> {
+        while vm_state.ip < program.len() {
+            let instr = Instr::parse(&program[vm_state.ip], vm_state.ip)?;
+            vm_state.step(instr)?;
+        }
+        Ok(())
+    }
+
+    fn ints(values: &[i64]) -> Vec<Value> {
+        values.iter().map(|&n| Value::Int(n)).collect()
+    }
+
+    fn time(f: impl FnOnce()) -> Duration {
+        let start = Instant::now();
+        f();
+        start.elapsed()
+    }
+
+    #[test]
+    fn countdown() {
+        let mut vm_state = VMState::default();
+        vm_state
+            .run(&instr::parse(&assemble(COUNTDOWN, 100)).unwrap())
+            .unwrap();
+        assert_eq!(vm_state.reg, Value::Int(0));
+        assert!(vm_state.stack.is_empty());
+    }
+
+    #[test]
+    fn errors_carry_ip() {
+        let program = instr::parse(&assemble("LOAD 1\nLOAD 0\nPOP\nDIV", 0)).unwrap();
+        assert_eq!(
+            VMState::default().run(&program),
+            Err(VmError::DivideByZero { ip: 3 })
+        );
+
+        let program =
+            instr::parse(&assemble("LOAD 9223372036854775807\nPOP\nLOAD 1\nADD", 0)).unwrap();
+        assert_eq!(
+            VMState::default().run(&program),
+            Err(VmError::Overflow { ip: 3, op: "ADD" })
+        );
+
+        assert_eq!(
+            VMState::default().run(&[Instr::Jmp(2)]),
+            Err(VmError::IpOutOfRange { ip: 0, target: 2 })
+        );
+        assert!(instr::parse(&["LOAD X".to_string()]).is_err());
+    }
+
+    #[test]
+    fn stack_and_memory() {
+        let source =
+            "SLOT A\nSLOT B 2\nLOAD 1\nLOAD 2\nOVER\nSWAP\nDUP\nTOP\nSTORE B+1\nPOP\nSTORE A";
+        let mut vm_state = VMState::default();
+        vm_state
+            .run(&instr::parse(&assemble(source, 0)).unwrap())
+            .unwrap();
+        assert_eq!(vm_state.stack, ints(&[1, 1, 2]));
+        assert_eq!(vm_state.memory[..3], ints(&[2, 0, 2])[..]);
+
+        // one element on the stack
+        for op in ["OVER", "SWAP"] {
+            let program = instr::parse(&assemble(&format!("LOAD 1\n{}", op), 0)).unwrap();
+            assert_eq!(
+                VMState::default().run(&program),
+                Err(VmError::StackUnderflow { ip: 1, op })
+            );
+        }
+        for op in ["DUP", "TOP"] {
+            let program = instr::parse(&[op.to_string()]).unwrap();
+            assert_eq!(
+                VMState::default().run(&program),
+                Err(VmError::StackUnderflow { ip: 0, op })
+            );
+        }
+        assert_eq!(
+            VMState::default().run(&[Instr::Fetch(MEMORY_SIZE)]),
+            Err(VmError::BadAddress {
+                ip: 0,
+                op: "FETCH",
+                addr: MEMORY_SIZE
+            })
+        );
+    }
+
+    #[test]
+    fn call_and_return() {
+        // twice(twice(3)), falls through into the procedure after it returns
+        let source = "LOAD 3\nPOP\nCALL QUAD\nJMP END\n\
+                      TWICE: PUSH\nADD\nRET\n\
+                      QUAD: CALL TWICE\nCALL TWICE\nRET\nEND:";
+        let mut vm_state = VMState::default();
+        vm_state
+            .run(&instr::parse(&assemble(source, 0)).unwrap())
+            .unwrap();
+        assert_eq!(vm_state.reg, Value::Int(12));
+        assert!(vm_state.call_stack.is_empty());
+
+        let program = instr::parse(&assemble("F: CALL F", 0)).unwrap();
+        let mut vm_state = VMState::with_limits(Limits {
+            max_call_depth: 8,
+            ..Limits::default()
+        });
+        assert_eq!(
+            vm_state.run(&program),
+            Err(VmError::CallDepth { ip: 0, max: 8 })
+        );
+        assert_eq!(vm_state.call_stack.len(), 8);
+        assert_eq!(
+            VMState::default().run(&[Instr::Ret]),
+            Err(VmError::ReturnWithoutCall { ip: 0 })
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let run_limited = |source: &str, limits: Limits| {
+            let program = instr::parse(&assemble(source, 0)).unwrap();
+            VMState::with_limits(limits).run(&program)
+        };
+
+        let limits = Limits {
+            max_steps: Some(100),
+            ..Limits::default()
+        };
+        assert_eq!(
+            run_limited("NOP: JMP NOP", limits),
+            Err(VmError::StepLimit { ip: 0, max: 100 })
+        );
+        assert_eq!(run_limited("LOAD 3\nPOP", limits), Ok(()));
+
+        let limits = Limits {
+            max_stack: 4,
+            ..Limits::default()
+        };
+        assert_eq!(
+            run_limited("LOOP: PUSH\nJMP LOOP", limits),
+            Err(VmError::StackOverflow {
+                ip: 0,
+                op: "PUSH",
+                max: 4
+            })
+        );
+
+        let limits = Limits {
+            memory: 16,
+            ..Limits::default()
+        };
+        assert_eq!(
+            run_limited("STORE 15\nSTORE 16", limits),
+            Err(VmError::MemoryLimit {
+                ip: 1,
+                op: "STORE",
+                addr: 16,
+                max: 16
+            })
+        );
+
+        let timeout = Duration::from_millis(20);
+        let limits = Limits {
+            timeout: Some(timeout),
+            ..Limits::default()
+        };
+        match run_limited("NOP: JMP NOP", limits) {
+            Err(VmError::Timeout { ip: 0, timeout: t }) => assert_eq!(t, timeout),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn typed_values() {
+        let run = |source: &str| {
+            let mut vm_state = VMState::default();
+            let result = vm_state.run(&instr::parse(&assemble(source, 0)).unwrap());
+            result.map(|_| vm_state.reg)
+        };
+
+        // beyond 32 bits, hexadecimal literals
+        assert_eq!(
+            run("LOAD 0x100000000\nLOAD -0x10\nPOP\nMUL"),
+            Ok(Value::Int(-(1 << 36)))
+        );
+        assert_eq!(run("LOAD 7.5\nSET 2.5\nSUB"), Ok(Value::Float(5.0)));
+        assert_eq!(
+            run("LOAD 1.0\nSET 0.0\nDIV"),
+            Ok(Value::Float(f64::INFINITY))
+        );
+        assert_eq!(run("LOAD 1.0\nSET 0\nDIV").unwrap_err().ip(), 2);
+        assert_eq!(
+            run("LOAD 1.5\nSET 2\nMUL"),
+            Err(VmError::TypeMismatch {
+                ip: 2,
+                op: "MUL",
+                left: "float",
+                right: "int"
+            })
+        );
+
+        // conversions
+        assert_eq!(
+            run("SET 7\nITOF\nPUSH\nSET 2.0\nDIV"),
+            Ok(Value::Float(3.5))
+        );
+        assert_eq!(run("SET -3.9\nFTOI"), Ok(Value::Int(-3)));
+        assert_eq!(
+            run("SET 1e19\nFTOI"),
+            Err(VmError::Overflow { ip: 1, op: "FTOI" })
+        );
+        assert_eq!(
+            run("SET 1\nFTOI"),
+            Err(VmError::WrongType {
+                ip: 1,
+                op: "FTOI",
+                expected: "float",
+                found: "int"
+            })
+        );
+        assert_eq!(
+            run("SET 0.0\nJZ 0"),
+            Err(VmError::WrongType {
+                ip: 1,
+                op: "JZ",
+                expected: "int",
+                found: "float"
+            })
+        );
+    }
+
+    /// Runs `op` with `left` on the stack (if any) and `right` in `reg`, returns `reg`.
+    fn eval(op: Instr, left: Option<Value>, right: Value) -> Result<Value, VmError> {
+        let mut vm_state = VMState::default();
+        vm_state.stack.extend(left);
+        vm_state.reg = right;
+        vm_state.run(&[op]).map(|_| vm_state.reg)
+    }
+
+    fn int_op(op: Instr, left: i64, right: i64) -> Result<Value, VmError> {
+        eval(op, Some(Value::Int(left)), Value::Int(right))
+    }
+
+    fn float_op(op: Instr, left: f64, right: f64) -> Result<Value, VmError> {
+        eval(op, Some(Value::Float(left)), Value::Float(right))
+    }
+
+    #[test]
+    fn op_mod() {
+        assert_eq!(int_op(Instr::Mod, 7, 3), Ok(Value::Int(1)));
+        assert_eq!(int_op(Instr::Mod, -7, 3), Ok(Value::Int(-1)));
+        assert_eq!(float_op(Instr::Mod, 7.5, 2.0), Ok(Value::Float(1.5)));
+        assert_eq!(
+            int_op(Instr::Mod, 7, 0),
+            Err(VmError::DivideByZero { ip: 0 })
+        );
+        assert_eq!(
+            int_op(Instr::Mod, i64::MIN, -1),
+            Err(VmError::Overflow { ip: 0, op: "MOD" })
+        );
+        assert_eq!(
+            eval(Instr::Mod, None, Value::Int(0)),
+            Err(VmError::StackUnderflow { ip: 0, op: "MOD" })
+        );
+    }
+
+    #[test]
+    fn op_neg() {
+        assert_eq!(eval(Instr::Neg, None, Value::Int(5)), Ok(Value::Int(-5)));
+        assert_eq!(
+            eval(Instr::Neg, None, Value::Float(-0.5)),
+            Ok(Value::Float(0.5))
+        );
+        assert_eq!(
+            eval(Instr::Neg, None, Value::Int(i64::MIN)),
+            Err(VmError::Overflow { ip: 0, op: "NEG" })
+        );
+    }
+
+    #[test]
+    fn op_eq() {
+        assert_eq!(int_op(Instr::Eq, 3, 3), Ok(Value::Int(1)));
+        assert_eq!(int_op(Instr::Eq, 3, 4), Ok(Value::Int(0)));
+        assert_eq!(float_op(Instr::Eq, 0.5, 0.5), Ok(Value::Int(1)));
+        assert_eq!(float_op(Instr::Eq, f64::NAN, f64::NAN), Ok(Value::Int(0)));
+        assert_eq!(
+            eval(Instr::Eq, Some(Value::Int(1)), Value::Float(1.0)),
+            Err(VmError::TypeMismatch {
+                ip: 0,
+                op: "EQ",
+                left: "int",
+                right: "float"
+            })
+        );
+    }
+
+    #[test]
+    fn op_lt() {
+        // the left operand is the popped one, as for SUB
+        assert_eq!(int_op(Instr::Lt, 2, 3), Ok(Value::Int(1)));
+        assert_eq!(int_op(Instr::Lt, 3, 3), Ok(Value::Int(0)));
+        assert_eq!(int_op(Instr::Lt, 4, 3), Ok(Value::Int(0)));
+        assert_eq!(float_op(Instr::Lt, -1.5, 0.0), Ok(Value::Int(1)));
+    }
+
+    #[test]
+    fn op_gt() {
+        assert_eq!(int_op(Instr::Gt, 4, 3), Ok(Value::Int(1)));
+        assert_eq!(int_op(Instr::Gt, 3, 3), Ok(Value::Int(0)));
+        assert_eq!(int_op(Instr::Gt, -4, 3), Ok(Value::Int(0)));
+        assert_eq!(float_op(Instr::Gt, 2.5, 2.0), Ok(Value::Int(1)));
+    }
+
+    #[test]
+    fn op_and() {
+        assert_eq!(int_op(Instr::And, 0b1100, 0b1010), Ok(Value::Int(0b1000)));
+        assert_eq!(int_op(Instr::And, -1, 42), Ok(Value::Int(42)));
+        assert_eq!(
+            float_op(Instr::And, 1.0, 1.0),
+            Err(VmError::WrongType {
+                ip: 0,
+                op: "AND",
+                expected: "int",
+                found: "float"
+            })
+        );
+    }
+
+    #[test]
+    fn op_or() {
+        assert_eq!(int_op(Instr::Or, 0b1100, 0b1010), Ok(Value::Int(0b1110)));
+        assert_eq!(int_op(Instr::Or, 0, 0), Ok(Value::Int(0)));
+    }
+
+    #[test]
+    fn op_xor() {
+        assert_eq!(int_op(Instr::Xor, 0b1100, 0b1010), Ok(Value::Int(0b0110)));
+        assert_eq!(int_op(Instr::Xor, 42, 42), Ok(Value::Int(0)));
+    }
+
+    #[test]
+    fn op_not() {
+        assert_eq!(eval(Instr::Not, None, Value::Int(0)), Ok(Value::Int(-1)));
+        assert_eq!(eval(Instr::Not, None, Value::Int(5)), Ok(Value::Int(-6)));
+        assert_eq!(
+            eval(Instr::Not, None, Value::Float(0.0)),
+            Err(VmError::WrongType {
+                ip: 0,
+                op: "NOT",
+                expected: "int",
+                found: "float"
+            })
+        );
+    }
+
+    #[test]
+    fn op_shl() {
+        assert_eq!(int_op(Instr::Shl, 3, 4), Ok(Value::Int(48)));
+        assert_eq!(int_op(Instr::Shl, 1, 63), Ok(Value::Int(i64::MIN)));
+        for amount in [64, -1] {
+            assert_eq!(
+                int_op(Instr::Shl, 1, amount),
+                Err(VmError::Overflow { ip: 0, op: "SHL" })
+            );
+        }
+    }
+
+    #[test]
+    fn op_shr() {
+        assert_eq!(int_op(Instr::Shr, 48, 4), Ok(Value::Int(3)));
+        assert_eq!(int_op(Instr::Shr, -16, 2), Ok(Value::Int(-4)));
+        assert_eq!(
+            int_op(Instr::Shr, 1, 64),
+            Err(VmError::Overflow { ip: 0, op: "SHR" })
+        );
+    }
+
+    #[test]
+    fn comparison_loop() {
+        // counts the multiples of 3 below 20 with LT and MOD
+        let source = "SLOT I\nSLOT N\n\
+                      LOOP: FETCH I\nPUSH\nLOAD 3\nPOP\nMOD\nJNZ NEXT\n\
+                      FETCH N\nPUSH\nSET 1\nADD\nSTORE N\n\
+                      NEXT: FETCH I\nPUSH\nSET 1\nADD\nSTORE I\nPUSH\nSET 20\nLT\nJNZ LOOP\n\
+                      FETCH N";
+        let mut vm_state = VMState::default();
+        vm_state
+            .run(&instr::parse(&assemble(source, 0)).unwrap())
+            .unwrap();
+        assert_eq!(vm_state.reg, Value::Int(7));
+        assert!(vm_state.stack.is_empty());
+    }
+
+    fn run_io(source: &str, input: &str) -> (Result<(), VmError>, String) {
+        let lines: Vec<String> = source.lines().map(String::from).collect();
+        let program = assembler::assemble(&lines).unwrap();
+        let mut vm_state = VMState::with_io(Limits::default(), MemoryIo::new(input));
+        vm_state.data = program.data;
+        let result = vm_state.run(&instr::parse(&program.instructions).unwrap());
+        (result, vm_state.io.output())
+    }
+
+    #[test]
+    fn input_and_output() {
+        let source = "PRINTS \"a: \"\nREAD\nPUSH\nPRINTS \"b: \"\nREAD\nADD\n\
+                      PRINTS \"sum: \"\nPRINT\nLOAD 955\nPOP\nPRINTC\nPRINTS \"a: \"\n\
+                      PRINTS \"\\\"quoted\\\"\\n\"";
+        let (result, output) = run_io(source, "20\n 22");
+        assert_eq!(result, Ok(()));
+        assert_eq!(output, "a: b: sum: 42\n\u{3bb}a: \"quoted\"\n");
+
+        assert_eq!(
+            run_io("READ", "x").0,
+            Err(VmError::BadInput {
+                ip: 0,
+                input: Some("x".to_string())
+            })
+        );
+        assert_eq!(
+            run_io("READ\nREAD", "1").0,
+            Err(VmError::BadInput { ip: 1, input: None })
+        );
+        assert_eq!(
+            run_io("LOAD -1\nPOP\nPRINTC", "").0,
+            Err(VmError::BadChar { ip: 2, value: -1 })
+        );
+    }
+
+    /// `cargo test --release -- --ignored --nocapture bench`
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_loops() {
+        const N: i32 = 5_000_000;
+
+        for (name, source) in [("countdown", COUNTDOWN), ("arith", ARITH)] {
+            let text = assemble(source, N);
+            let code = instr::parse(&text).unwrap();
+
+            let mut reference = VMState::default();
+            let t_text = time(|| run_text(&mut reference, &text).unwrap());
+            let mut vm_state = VMState::default();
+            let t_code = time(|| vm_state.run(&code).unwrap());
+
+            assert_eq!(vm_state.reg, reference.reg);
+            assert_eq!(vm_state.stack, reference.stack);
+            println!(
+                "{:>10}: text {:>8.1?}  typed {:>8.1?}  speedup {:.1}x",
+                name,
+                t_text,
+                t_code,
+                t_text.as_secs_f64() / t_code.as_secs_f64()
+            );
+        }
+    }
+}